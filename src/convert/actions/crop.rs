@@ -34,6 +34,17 @@ fn move_tiles(db: &Database, dx: u64, dy: u64, new_level: u64) -> Result<()> {
     Ok(())
 }
 
+pub fn crop_offset(db: &Database) -> Result<(u64, u64)> {
+    let metadata = db.read_metadata()?;
+    let read = |key: &str| -> Result<u64> {
+        match metadata.get(key) {
+            Some(v) => Ok(v.parse::<u64>()?),
+            None => Ok(0),
+        }
+    };
+    Ok((read("crop_x")?, read("crop_y")?))
+}
+
 pub fn crop(db: &mut Database) -> Result<()> {
     let tile_size = db.tile_size();
 
@@ -44,7 +55,12 @@ pub fn crop(db: &mut Database) -> Result<()> {
 
     let tree_size = std::cmp::max(tile_count.0, tile_count.1);
     let new_level = (tree_size as f64).log2().ceil() as u64;
+
+    // Moving the tiles and updating the metadata happens in one transaction,
+    // so an interrupted crop can simply be run again.
+    db.connection().execute("BEGIN")?;
     move_tiles(db, min_x, min_y, new_level)?;
+    db.clear_cache();
 
     log::debug!(
         "Cropping Slide from {}x{} to {}x{}",
@@ -61,5 +77,11 @@ pub fn crop(db: &mut Database) -> Result<()> {
     db.set_meta("levels", &(new_level + 1).to_string())?;
     db.set_meta("width", &size.0.to_string())?;
     db.set_meta("height", &size.1.to_string())?;
+
+    // Offset of the cropped slide in the coordinates of the original slide.
+    let (offset_x, offset_y) = crop_offset(db)?;
+    db.set_meta("crop_x", &(offset_x + min_x * tile_size).to_string())?;
+    db.set_meta("crop_y", &(offset_y + min_y * tile_size).to_string())?;
+    db.connection().execute("COMMIT")?;
     Ok(())
 }
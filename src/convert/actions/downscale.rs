@@ -1,9 +1,11 @@
 use image::{imageops, ImageBuffer, Rgb, RgbImage};
+use std::collections::HashSet;
 
 use anyhow::Result;
 
-use crate::convert::LockFile;
-use crate::{Database, Tile};
+use super::crop_offset;
+use crate::convert::{Config, LockFile, SlideSource};
+use crate::{Database, Tile, TileWriter};
 
 fn combine_into(tile: &mut Tile, tiles: Vec<Tile>) -> Result<()> {
     let is_empty = tiles.iter().all(|t| t.is_empty());
@@ -36,7 +38,107 @@ fn combine_into(tile: &mut Tile, tiles: Vec<Tile>) -> Result<()> {
     Ok(())
 }
 
-fn compute_tile(db: &Database, tile: &mut Tile, lock: &mut LockFile) -> Result<()> {
+/// Reads downscaled tiles from the pyramid levels stored in the slide.
+/// The slide also shows background and removed islands, so everything not covered
+/// by a stored full resolution tile is masked white.
+struct NativeLevels<'a> {
+    slide: &'a dyn SlideSource,
+    downsamples: Vec<f64>,
+    offset: (u64, u64),
+    max_level: u64,
+    stored: HashSet<(u64, u64)>,
+}
+
+impl<'a> NativeLevels<'a> {
+    fn new(slide: &'a dyn SlideSource, db: &Database) -> Result<NativeLevels<'a>> {
+        let mut downsamples = Vec::new();
+        for level in 0..slide.level_count()? {
+            downsamples.push(slide.level_downsample(level)?);
+        }
+        log::debug!("Native pyramid downsamples: {:?}", downsamples);
+        let max_level = db.levels() - 1;
+        Ok(NativeLevels {
+            slide,
+            downsamples,
+            offset: crop_offset(db)?,
+            max_level,
+            stored: db.list_tiles(max_level)?.into_iter().collect(),
+        })
+    }
+
+    /// Paints the parts of the tile white which contain no stored full resolution tile.
+    fn mask(&self, tile: &Tile, image: &mut RgbImage) {
+        let n = 2u64.pow((self.max_level - tile.level()) as u32);
+        let size = tile.size();
+        // Full resolution tiles smaller than a pixel are masked in groups.
+        let blocks = std::cmp::min(n, size);
+        let (x0, y0) = (tile.pos().0 * n, tile.pos().1 * n);
+        let white = Rgb([255, 255, 255]);
+        for by in 0..blocks {
+            for bx in 0..blocks {
+                let cells_x = bx * n / blocks..(bx + 1) * n / blocks;
+                let cells_y = by * n / blocks..(by + 1) * n / blocks;
+                let stored = cells_y.clone().any(|cy| {
+                    cells_x
+                        .clone()
+                        .any(|cx| self.stored.contains(&(x0 + cx, y0 + cy)))
+                });
+                if stored {
+                    continue;
+                }
+                for py in by * size / blocks..(by + 1) * size / blocks {
+                    for px in bx * size / blocks..(bx + 1) * size / blocks {
+                        image.put_pixel(px as u32, py as u32, white);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads the tile from the nearest native level with a downsample factor <= the target,
+    /// returns None if only the full resolution level qualifies.
+    fn read(&self, tile: &Tile) -> Result<Option<RgbImage>> {
+        let target = 2u64.pow((self.max_level - tile.level()) as u32) as f64;
+        let native = self
+            .downsamples
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| **d <= target * (1.0 + 1e-3))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        let (native_level, downsample) = match native {
+            Some((l, d)) if *d > 1.0 + 1e-3 => (l as i32, *d),
+            _ => return Ok(None),
+        };
+
+        let size = tile.size();
+        let (x, y) = tile.coords();
+        let x0 = self.offset.0 + (x as f64 * target) as u64;
+        let y0 = self.offset.1 + (y as f64 * target) as u64;
+        let read_size = (size as f64 * target / downsample).round() as i64;
+        let image = self.slide.read_region_level(
+            x0 as i64,
+            y0 as i64,
+            native_level,
+            read_size,
+            read_size,
+        )?;
+        let s = size as u32;
+        let mut image = match read_size as u64 == size {
+            true => image,
+            false => imageops::resize(&image, s, s, imageops::FilterType::Lanczos3),
+        };
+        self.mask(tile, &mut image);
+        Ok(Some(image))
+    }
+}
+
+fn compute_tile(
+    db: &Database,
+    writer: &mut TileWriter,
+    native: Option<&NativeLevels>,
+    tile: &mut Tile,
+    lock: &mut LockFile,
+) -> Result<()> {
     lock.inc()?;
 
     let pos = tile.pos();
@@ -60,16 +162,44 @@ fn compute_tile(db: &Database, tile: &mut Tile, lock: &mut LockFile) -> Result<(
             let sx = 2 * pos.0 + dx;
             let sy = 2 * pos.1 + dy;
             let mut sub_tile = Tile::new((sx, sy), sub_level, size);
-            compute_tile(db, &mut sub_tile, lock)?;
+            compute_tile(db, writer, native, &mut sub_tile, lock)?;
             sub_tiles.push(sub_tile);
         }
     }
-    combine_into(tile, sub_tiles)?;
-    db.write(tile)?;
+    let native_image = match native {
+        Some(n) if sub_tiles.iter().any(|t| !t.is_empty()) => n.read(tile)?,
+        _ => None,
+    };
+    match native_image {
+        Some(image) => tile.set_image(image)?,
+        None => combine_into(tile, sub_tiles)?,
+    };
+    writer.write(tile)?;
     Ok(())
 }
 
-pub fn downscale(db: &Database, lock: &mut LockFile) -> Result<()> {
+pub fn downscale(db: &Database, config: &Config, lock: &mut LockFile) -> Result<()> {
+    run_downscale(db, None, config, lock)
+}
+
+/// Like `downscale`, but lower resolution tiles are read from the slide's native pyramid
+/// where possible and only resampled between native levels.
+pub fn downscale_native(
+    db: &Database,
+    slide: &dyn SlideSource,
+    config: &Config,
+    lock: &mut LockFile,
+) -> Result<()> {
+    let native = NativeLevels::new(slide, db)?;
+    run_downscale(db, Some(&native), config, lock)
+}
+
+fn run_downscale(
+    db: &Database,
+    native: Option<&NativeLevels>,
+    config: &Config,
+    lock: &mut LockFile,
+) -> Result<()> {
     let mut total_nodes: u64 = 0;
     let base: u64 = 4;
     let levels = db.levels();
@@ -81,7 +211,9 @@ pub fn downscale(db: &Database, lock: &mut LockFile) -> Result<()> {
     lock.state("Downscaling")?;
     lock.start(total_nodes)?;
     let mut root_tile = Tile::new((0, 0), 0, tile_size);
-    compute_tile(db, &mut root_tile, lock)?;
+    let mut writer = db.writer(config.batch_size)?;
+    compute_tile(db, &mut writer, native, &mut root_tile, lock)?;
+    writer.finish()?;
     lock.finish()?;
     Ok(())
 }
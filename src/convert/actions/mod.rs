@@ -1,8 +1,8 @@
 mod downscale;
-pub use downscale::downscale;
+pub use downscale::{downscale, downscale_native};
 
 mod crop;
-pub use crop::crop;
+pub use crop::{crop, crop_offset};
 
 mod read_slide;
 pub use read_slide::read_slide;
@@ -1,55 +1,72 @@
-use crate::convert::{Config, LockFile, OpenSlide};
-use crate::{Database, Tile};
+use crate::convert::{tissue_detector, Config, LockFile, SlideSource, TissueDetector};
+use crate::{Database, Tile, TileCodec};
 use anyhow::Result;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
 
-use image::{imageops, GrayImage, Pixel, RgbImage};
-use imageops::FilterType;
-use imageproc::edges::canny;
+/// Metadata key set if tiles of the slide were stored without re-encoding.
+const PASSTHROUGH_KEY: &str = "jpeg_passthrough";
 
-pub fn is_valid_image(image: &RgbImage, config: &Config) -> bool {
-    let (w, h) = image.dimensions();
-    let mut gray = GrayImage::new(w, h);
-
-    let threshold = (255.0 * config.dark_threshold) as u8;
-    let mut dark_pixels = 0;
-
-    for (g_p, rgb_p) in gray.pixels_mut().zip(image.pixels()) {
-        *g_p = rgb_p.to_luma();
-        if g_p[0] < threshold {
-            dark_pixels += 1;
+/// Reads the tile at `pos`, returns its encoded data and whether the data was passed through.
+fn read_tile(
+    slide: &dyn SlideSource,
+    pos: (u64, u64),
+    level: u64,
+    tile_size: u64,
+    codec: TileCodec,
+    detector: &dyn TissueDetector,
+    config: &Config,
+) -> Result<Option<(Vec<u8>, bool)>> {
+    let mut tile = Tile::new(pos, level, tile_size);
+    let (x, y) = tile.coords();
+    // Tiles of the slide are stored as they are, the filter still runs on the pixels.
+    if config.passthrough && matches!(codec, TileCodec::Jpeg { .. }) {
+        if let Some(data) = slide.read_raw_tile(x as i64, y as i64, tile_size)? {
+            if !detector.is_tissue(&codec.decode(&data)?) {
+                return Ok(None);
+            }
+            return Ok(Some((data, true)));
         }
     }
-    let dark_content = dark_pixels as f64 / (w as f64 * h as f64);
-    if dark_content < config.min_dark_content {
-        return false;
+    let image = slide.read_region(x as i64, y as i64, tile_size as i64, tile_size as i64)?;
+    if !detector.is_tissue(&image) {
+        return Ok(None);
     }
+    tile.set_image(image)?;
+    Ok(Some((tile.encode(codec)?, false)))
+}
 
-    let size = config.edge_detect_size as u32;
-    let resized = imageops::resize(&gray, size, size, FilterType::Triangle);
-    let edges = canny(
-        &resized,
-        config.edge_low_threshold,
-        config.edge_high_threshold,
-    );
-    let edge_count: u64 = edges
-        .into_raw()
-        .iter()
-        .map(|p| if *p == 0 { 0 } else { 1 })
-        .sum();
-    let edge_percent = (edge_count as f64) / (size * size) as f64;
-    return edge_percent > config.min_edge_content;
+fn last_tile(db: &Database, level: u64) -> Result<Option<(u64, u64)>> {
+    let statement = "SELECT x, y from tiles WHERE
+            level = ?
+        ORDER BY x DESC, y DESC
+        LIMIT 1
+    ";
+    let mut statement = db.connection().prepare(statement)?;
+    statement.bind((1, level as i64))?;
+    match statement.next()? {
+        sqlite::State::Row => {
+            let x = statement.read::<i64, _>(0)? as u64;
+            let y = statement.read::<i64, _>(1)? as u64;
+            Ok(Some((x, y)))
+        }
+        sqlite::State::Done => Ok(None),
+    }
 }
 
 pub fn read_slide(
-    slide: &OpenSlide,
+    slide: &dyn SlideSource,
     db: &Database,
     config: &Config,
     lock: &mut LockFile,
 ) -> Result<()> {
     let tile_size = db.tile_size();
+    let codec = db.codec();
     let width = db.width();
     let height = db.height();
-    let levels = db.levels();
+    let level = db.levels() - 1;
     let tiles_x = (width as f64 / tile_size as f64).ceil() as u64;
     let tiles_y = (height as f64 / tile_size as f64).ceil() as u64;
 
@@ -57,20 +74,93 @@ pub fn read_slide(
     lock.state("Reading")?;
     lock.start(total)?;
 
-    for tx in 0..tiles_x {
-        for ty in 0..tiles_y {
-            lock.inc()?;
-            let mut tile = Tile::new((tx, ty), levels - 1, tile_size);
-            let (x, y) = tile.coords();
-            let image =
-                slide.read_region(x as i64, y as i64, tile_size as i64, tile_size as i64)?;
-            if !is_valid_image(&image, config) {
-                continue;
-            }
-            tile.set_image(image)?;
-            db.write(&tile)?;
-        }
+    // Tiles are written in order, so reading continues after the last stored tile.
+    let first = match last_tile(db, level)? {
+        Some((x, y)) => x * tiles_y + y + 1,
+        None => 0,
+    };
+    if first > 0 {
+        log::info!("Skipping {} tiles read previously", first);
+        lock.current = first;
     }
+
+    if config.passthrough && !matches!(codec, TileCodec::Jpeg { .. }) {
+        log::warn!(
+            "Tiles are encoded as {}, JPEG pass-through is disabled",
+            codec
+        );
+    }
+
+    let detector = tissue_detector(slide, config)?;
+    let detector = detector.as_ref();
+
+    let threads = config.thread_count();
+    log::debug!("Reading {} tiles with {} threads", total, threads);
+    let position = |i: u64| (i / tiles_y, i % tiles_y);
+    let next = AtomicU64::new(first);
+    // Workers stay at most `window` tiles ahead of the writer, so a slow tile
+    // can not make the buffer of tiles waiting to be written grow without bound.
+    let window = 4 * threads as u64;
+    let written = (Mutex::new(first), Condvar::new());
+
+    // Workers read, filter and encode tiles, the calling thread writes them in order.
+    thread::scope(|scope| -> Result<()> {
+        let (sender, receiver) = mpsc::sync_channel(window as usize);
+        for _ in 0..threads {
+            let sender = sender.clone();
+            let next = &next;
+            let written = &written;
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= total {
+                    break;
+                }
+                let (current, ready) = written;
+                let mut current = current.lock().unwrap();
+                while i >= current.saturating_add(window) {
+                    current = ready.wait(current).unwrap();
+                }
+                drop(current);
+                let pos = position(i);
+                let result = read_tile(slide, pos, level, tile_size, codec, detector, config);
+                if sender.send((i, result)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let write = || -> Result<()> {
+            let mut writer = db.writer(config.batch_size)?;
+            let mut pending = BTreeMap::new();
+            let mut current = first;
+            let mut passed_through = false;
+            for (i, result) in receiver {
+                pending.insert(i, result);
+                while let Some(result) = pending.remove(&current) {
+                    lock.inc()?;
+                    if let Some((data, raw)) = result? {
+                        // Passed through tiles keep the JPEG quality of the scanner.
+                        if raw && !passed_through {
+                            db.set_meta(PASSTHROUGH_KEY, "true")?;
+                            passed_through = true;
+                        }
+                        let tile = Tile::new(position(current), level, tile_size);
+                        writer.write_data(&tile, &data)?;
+                    }
+                    current += 1;
+                    *written.0.lock().unwrap() = current;
+                    written.1.notify_all();
+                }
+            }
+            writer.finish()
+        };
+        let result = write();
+        // Releases waiting workers if writing failed.
+        *written.0.lock().unwrap() = u64::MAX;
+        written.1.notify_all();
+        result
+    })?;
     lock.finish()?;
     Ok(())
 }
@@ -5,8 +5,8 @@ use std::collections::HashSet;
 
 fn get_size(graph: &HashSet<(u64, u64)>) -> (u64, u64) {
     let first = graph.iter().next().unwrap();
-    let mut max = first.clone();
-    let mut min = first.clone();
+    let mut max = *first;
+    let mut min = *first;
     for node in graph {
         let (x, y) = node;
         if *x > max.0 {
@@ -22,8 +22,7 @@ fn get_size(graph: &HashSet<(u64, u64)>) -> (u64, u64) {
             min.1 = *y;
         }
     }
-    let size = (max.0 - min.0 + 1, max.1 - min.1 + 1);
-    size
+    (max.0 - min.0 + 1, max.1 - min.1 + 1)
 }
 
 fn find_connected_subgraphs(
@@ -36,28 +35,24 @@ fn find_connected_subgraphs(
 
     while !graph.is_empty() {
         let first = graph.iter().next().unwrap();
-        let mut stack = vec![first.clone()];
+        let mut stack = vec![*first];
 
         let mut subgraph = HashSet::new();
-        while !stack.is_empty() {
-            let node = stack.pop().unwrap();
-            match graph.take(&node) {
-                Some(n) => {
-                    lock.inc()?;
-                    subgraph.insert(n);
-                    let (ux, uy) = n;
-                    let x = ux as i64;
-                    let y = uy as i64;
-                    let neighbors = vec![(x, y + 1), (x + 1, y), (x, y - 1), (x - 1, y)];
-                    for n in neighbors {
-                        if n.0 < 0 || n.1 < 0 {
-                            continue;
-                        }
-                        stack.push((n.0 as u64, n.1 as u64));
+        while let Some(node) = stack.pop() {
+            if let Some(n) = graph.take(&node) {
+                lock.inc()?;
+                subgraph.insert(n);
+                let (ux, uy) = n;
+                let x = ux as i64;
+                let y = uy as i64;
+                let neighbors = vec![(x, y + 1), (x + 1, y), (x, y - 1), (x - 1, y)];
+                for n in neighbors {
+                    if n.0 < 0 || n.1 < 0 {
+                        continue;
                     }
+                    stack.push((n.0 as u64, n.1 as u64));
                 }
-                None => {}
-            };
+            }
         }
         subgraphs.push(subgraph);
     }
@@ -81,7 +76,7 @@ pub fn remove_islands(db: &Database, c: &Config, lock: &mut LockFile) -> Result<
         if size.0 < c.island_size || size.1 < c.island_size {
             log::debug!("Removing island {}x{} {} tiles", size.0, size.1, n,);
             for node in &graph {
-                db.delete(node.clone(), level)?;
+                db.delete(*node, level)?;
             }
         }
     }
@@ -2,6 +2,10 @@ use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, fs::File, path::PathBuf};
 
+use super::DetectorKind;
+use crate::types::Stain;
+use crate::{TileCodec, DEFAULT_BATCH_SIZE};
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     pub tile_size: u64,
@@ -13,12 +17,52 @@ pub struct Config {
     pub min_dark_content: f64,
     pub island_size: u64,
     pub island_tiles: u64,
+    /// Number of threads reading tiles, 0 uses all available cores
+    #[serde(default)]
+    pub threads: u64,
+    /// Number of tiles written per transaction
+    #[serde(default = "default_batch_size")]
+    pub batch_size: u64,
+    /// Use a write-ahead log with relaxed syncing while converting
+    #[serde(default)]
+    pub wal: bool,
+    /// Read lower resolution levels from the slide's own pyramid instead of downscaling
+    #[serde(default)]
+    pub native_levels: bool,
+    /// Encoding of the stored tiles, "jpeg:<quality>", "png" or "webp"
+    #[serde(default)]
+    pub codec: TileCodec,
+    /// Store the JPEG tiles of the slide without re-encoding, if they are aligned with the tiles
+    #[serde(default)]
+    pub passthrough: bool,
+    /// Stain of the slide, selects the default tissue detector
+    #[serde(default)]
+    pub stain: Option<Stain>,
+    /// Tissue detector, "edge", "otsu" or "combined", by default chosen by the stain
+    #[serde(default)]
+    pub tissue_detector: Option<DetectorKind>,
+    /// Lower bound of the saturation threshold found on the overview, from 0 to 1
+    #[serde(default = "default_min_saturation")]
+    pub min_saturation: f64,
+    /// Share of pixels above the saturation threshold a tile needs to count as tissue
+    #[serde(default = "default_min_tissue_content")]
+    pub min_tissue_content: f64,
 }
 
-impl Config {
-    pub fn default() -> Config {
+fn default_batch_size() -> u64 {
+    DEFAULT_BATCH_SIZE
+}
+fn default_min_saturation() -> f64 {
+    0.05
+}
+fn default_min_tissue_content() -> f64 {
+    0.2
+}
+
+impl Default for Config {
+    fn default() -> Config {
         log::debug!("Loading default config");
-        let c = Config {
+        Config {
             tile_size: 512,
             edge_detect_size: 64,
             edge_low_threshold: 5.0,
@@ -28,9 +72,21 @@ impl Config {
             min_dark_content: 0.30,
             island_size: 5,
             island_tiles: 15,
-        };
-        c
+            threads: 0,
+            batch_size: DEFAULT_BATCH_SIZE,
+            wal: false,
+            native_levels: false,
+            codec: TileCodec::default(),
+            passthrough: false,
+            stain: None,
+            tissue_detector: None,
+            min_saturation: default_min_saturation(),
+            min_tissue_content: default_min_tissue_content(),
+        }
     }
+}
+
+impl Config {
     pub fn from(path: PathBuf) -> Result<Config> {
         log::debug!("Reading config from {}", path.display());
         if !path.is_file() {
@@ -41,6 +97,24 @@ impl Config {
         Ok(c)
     }
 
+    pub fn thread_count(&self) -> usize {
+        match self.threads {
+            0 => std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            n => n as usize,
+        }
+    }
+
+    /// The selected tissue detector, or the default of the stain.
+    pub fn detector_kind(&self) -> DetectorKind {
+        match (self.tissue_detector, self.stain) {
+            (Some(kind), _) => kind,
+            (None, Some(stain)) => DetectorKind::for_stain(stain),
+            (None, None) => DetectorKind::Edge,
+        }
+    }
+
     pub fn to_hash_map(&self) -> Result<HashMap<String, String>> {
         let mut map = HashMap::new();
         map.insert("tile_size".to_owned(), self.tile_size.to_string());
@@ -67,6 +141,24 @@ impl Config {
         );
         map.insert("island_size".to_owned(), self.island_size.to_string());
         map.insert("island_tiles".to_owned(), self.island_tiles.to_string());
+        map.insert("threads".to_owned(), self.threads.to_string());
+        map.insert("batch_size".to_owned(), self.batch_size.to_string());
+        map.insert("wal".to_owned(), self.wal.to_string());
+        map.insert("native_levels".to_owned(), self.native_levels.to_string());
+        map.insert("codec".to_owned(), self.codec.to_string());
+        map.insert("passthrough".to_owned(), self.passthrough.to_string());
+        if let Some(stain) = self.stain {
+            map.insert("stain".to_owned(), stain.to_string());
+        }
+        map.insert(
+            "tissue_detector".to_owned(),
+            self.detector_kind().to_string(),
+        );
+        map.insert("min_saturation".to_owned(), self.min_saturation.to_string());
+        map.insert(
+            "min_tissue_content".to_owned(),
+            self.min_tissue_content.to_string(),
+        );
         Ok(map)
     }
 }
@@ -1,21 +1,65 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 use super::actions;
 use super::LockFile;
 
 use crate::database::SlideData;
-use crate::Database;
+use crate::{Database, PROPERTY_PREFIX};
 
-use super::{Config, OpenSlide};
+use super::{open_passthrough, open_slide, Config, SlideSource};
+
+/// Opens the slide, with JPEG pass-through the compressed tiles of TIFF files are read as well.
+fn open_source(path: &PathBuf, config: &Config) -> Result<Box<dyn SlideSource>> {
+    match config.passthrough {
+        true => open_passthrough(path),
+        false => open_slide(path),
+    }
+}
 
 pub fn convert(slide_path: PathBuf, db_path: PathBuf, config: &Config) -> Result<()> {
-    let slide = OpenSlide::open(&slide_path)?;
+    let slide = open_source(&slide_path, config)?;
+    create_and_convert(slide.as_ref(), Some(&slide_path), db_path, config)
+}
+
+/// Converts a slide that is not backed by a file, e.g. an in memory `ImageSlide`.
+/// The conversion can not be resumed, since the slide can not be reopened.
+pub fn convert_source(slide: &dyn SlideSource, db_path: PathBuf, config: &Config) -> Result<()> {
+    create_and_convert(slide, None, db_path, config)
+}
+
+fn create_and_convert(
+    slide: &dyn SlideSource,
+    slide_path: Option<&PathBuf>,
+    db_path: PathBuf,
+    config: &Config,
+) -> Result<()> {
+    let mut db = create_database(slide, slide_path, &db_path, config)?;
+    let mut lock = LockFile::lock(&db_path, "Init")?;
+    run_stages(slide, &mut db, config, &mut lock, 0)?;
+    lock.release()?;
 
+    Ok(())
+}
+
+/// Creates the database of a slide with its metadata, but without tiles.
+fn create_database(
+    slide: &dyn SlideSource,
+    slide_path: Option<&PathBuf>,
+    db_path: &PathBuf,
+    config: &Config,
+) -> Result<Database> {
     let tile_size = config.tile_size;
 
-    let (x_ppm, y_ppm) = slide.get_resolution()?;
+    let (x_ppm, y_ppm) = match slide.resolution() {
+        Ok(resolution) => resolution,
+        Err(e) => {
+            log::warn!("Unknown resolution: {}", e);
+            (0, 0)
+        }
+    };
     let (width, height) = slide.size();
 
     let tiles_x = (width as f64 / tile_size as f64).ceil() as u64;
@@ -23,29 +67,461 @@ pub fn convert(slide_path: PathBuf, db_path: PathBuf, config: &Config) -> Result
     let tree_size = std::cmp::max(tiles_x, tiles_y);
     let levels = 1 + (tree_size as f64).log2().ceil() as u64;
 
-    log::debug!("Detected slide from vendor '{}'", slide.vendor);
-    log::debug!("  size (w x h): {}x{}", slide.width, slide.height);
+    log::debug!("Detected slide from vendor '{}'", slide.vendor());
+    log::debug!("  size (w x h): {}x{}", width, height);
     log::debug!("  resolution:   {}x{}", x_ppm, y_ppm);
     log::debug!("  tiles:        {}x{}", tiles_x, tiles_y);
     log::debug!("  levels:       {}", levels);
 
-    let slide_data = SlideData::new(tile_size, levels, width, height, x_ppm, y_ppm);
-    let mut db = Database::create(&db_path, slide_data)?;
+    let slide_data =
+        SlideData::new(tile_size, levels, width, height, x_ppm, y_ppm).with_codec(config.codec);
+    let db = Database::create(db_path, slide_data)?;
+    if config.wal {
+        db.enable_wal()?;
+    }
 
     let mut config_map = config.to_hash_map()?;
-    let path_str = std::fs::canonicalize(slide_path)?
-        .to_string_lossy()
-        .to_string();
-    config_map.insert("slide_path".to_owned(), path_str);
+    if let Some(path) = slide_path {
+        config_map.insert("slide_path".to_owned(), canonical_path(path)?);
+    }
+    db.write_metadata(config_map)?;
+    write_slide_properties(slide, &db)?;
+    Ok(db)
+}
 
-    let mut lock = LockFile::lock(&db_path, "Init")?;
-    actions::read_slide(&slide, &db, config, &mut lock)?;
-    actions::remove_islands(&db, config, &mut lock)?;
-    actions::crop(&mut db)?;
-    actions::downscale(&db, &mut lock)?;
+/// Continues an interrupted conversion from the stage recorded in its lockfile.
+/// Tiles already written to the database are kept.
+pub fn resume(slide_path: PathBuf, db_path: PathBuf, config: &Config) -> Result<()> {
+    let mut lock = match LockFile::find(&db_path)? {
+        Some(lock) if db_path.is_file() => lock,
+        _ => {
+            log::info!("Nothing to resume, starting a new conversion");
+            return convert(slide_path, db_path, config);
+        }
+    };
+    let slide = open_source(&slide_path, config)?;
+    let mut db = Database::open_readwrite(&db_path)?;
 
-    db.write_metadata(config_map)?;
+    let metadata = db.read_metadata()?;
+    let path_str = canonical_path(&slide_path)?;
+    if let Some(p) = metadata.get("slide_path") {
+        if *p != path_str {
+            bail!("{} was converted from {}", db_path.display(), p);
+        }
+    }
+    if config.wal {
+        db.enable_wal()?;
+    }
+
+    let stage = STAGES.iter().position(|s| *s == lock.state).unwrap_or(0);
+    log::info!("Resuming conversion at stage '{}'", STAGES[stage]);
+    lock.error = None;
+    run_stages(slide.as_ref(), &mut db, config, &mut lock, stage)?;
     lock.release()?;
+    Ok(())
+}
+
+const STAGES: [&str; 4] = ["Reading", "Island removal", "Cropping", "Downscaling"];
+
+fn run_stages(
+    slide: &dyn SlideSource,
+    db: &mut Database,
+    config: &Config,
+    lock: &mut LockFile,
+    first_stage: usize,
+) -> Result<()> {
+    if first_stage == 0 {
+        actions::read_slide(slide, db, config, lock)?;
+    }
+    if first_stage <= 1 {
+        actions::remove_islands(db, config, lock)?;
+    }
+    if first_stage <= 2 {
+        lock.state(STAGES[2])?;
+        actions::crop(db)?;
+    }
+    if config.native_levels {
+        actions::downscale_native(db, slide, config, lock)?;
+    } else {
+        actions::downscale(db, config, lock)?;
+    }
+
+    if config.wal {
+        db.disable_wal()?;
+    }
+    Ok(())
+}
 
+/// Copies the vendor properties and associated images of the slide into the database.
+fn write_slide_properties(slide: &dyn SlideSource, db: &Database) -> Result<()> {
+    let properties: HashMap<String, String> = slide
+        .properties()?
+        .into_iter()
+        .map(|(k, v)| (format!("{}{}", PROPERTY_PREFIX, k), v))
+        .collect();
+    db.write_metadata(properties)?;
+    for name in slide.associated_image_names()? {
+        log::debug!("Storing associated image '{}'", name);
+        let image = slide.read_associated_image(&name)?;
+        db.write_associated_image(&name, &image)?;
+    }
     Ok(())
 }
+
+fn canonical_path(path: &PathBuf) -> Result<String> {
+    let path_str = std::fs::canonicalize(path)?.to_string_lossy().to_string();
+    Ok(path_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{convert, convert_source, create_database, resume, STAGES};
+    use crate::convert::{
+        crop, crop_offset, open_slide, read_slide, remove_islands, Config, DetectorKind, LockFile,
+        Shape, SynthSlide, SYNTH_PPM,
+    };
+    use crate::types::Stain;
+    use crate::Database;
+    use anyhow::Result;
+    use image::{imageops, RgbImage};
+    use std::path::PathBuf;
+
+    const TILE_SIZE: u64 = 32;
+
+    /// Every conversion gets its own directory, the lockfile is shared per directory.
+    fn test_dir(name: &str) -> Result<PathBuf> {
+        let dir = std::env::temp_dir().join(format!("pamly_{}_{}", name, std::process::id()));
+        if dir.is_dir() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        std::fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    fn test_config() -> Config {
+        let mut config = Config::default();
+        config.tile_size = TILE_SIZE;
+        config.edge_detect_size = TILE_SIZE;
+        config.island_size = 3;
+        config.island_tiles = 4;
+        config.threads = 2;
+        config.batch_size = 5;
+        config
+    }
+
+    fn tiles(x: u64, y: u64, w: u64, h: u64) -> Shape {
+        Shape::Rect {
+            x: x * TILE_SIZE,
+            y: y * TILE_SIZE,
+            width: w * TILE_SIZE,
+            height: h * TILE_SIZE,
+        }
+    }
+
+    /// A 8x8 tile slide with a 4x4 tile blob at (2, 1), a single tile island at (0, 7)
+    /// and a 2x1 tile island at (6, 6).
+    fn test_slide() -> SynthSlide {
+        SynthSlide::new(8 * TILE_SIZE, 8 * TILE_SIZE, 1)
+            .with_shape(tiles(2, 1, 4, 4))
+            .with_shape(tiles(0, 7, 1, 1))
+            .with_shape(tiles(6, 6, 2, 1))
+    }
+
+    fn mean_difference(a: &RgbImage, b: &RgbImage) -> f64 {
+        let total: u64 = a
+            .as_raw()
+            .iter()
+            .zip(b.as_raw())
+            .map(|(x, y)| x.abs_diff(*y) as u64)
+            .sum();
+        total as f64 / a.as_raw().len() as f64
+    }
+
+    #[test]
+    fn convert_synthetic_slide() -> Result<()> {
+        let dir = test_dir("convert_synth")?;
+        let db_path = dir.join("slide.sqlite");
+        let slide = test_slide();
+        let image = slide.render();
+        convert_source(&slide.to_source(), db_path.clone(), &test_config())?;
+        assert!(!dir.join("pamly.lock").exists());
+
+        let db = Database::open(&db_path)?;
+        // Only the 4x4 blob survives, cropping turns it into a 3 level pyramid.
+        assert_eq!(db.levels(), 3);
+        assert_eq!((db.width(), db.height()), (4 * TILE_SIZE, 4 * TILE_SIZE));
+        assert_eq!(db.tile_counts()?, vec![(0, 1), (1, 4), (2, 16)]);
+        assert_eq!(crop_offset(&db)?, (2 * TILE_SIZE, TILE_SIZE));
+        assert_eq!(db.data.x_ppm, SYNTH_PPM);
+
+        let mut positions = db.list_tiles(2)?;
+        positions.sort();
+        let expected: Vec<(u64, u64)> = (0..4).flat_map(|x| (0..4).map(move |y| (x, y))).collect();
+        assert_eq!(positions, expected);
+
+        // Full resolution tiles match the slide at the crop offset, up to jpeg compression.
+        for (x, y) in [(0, 0), (3, 2)] {
+            let tile = db.read((x, y), 2)?;
+            let source = imageops::crop_imm(
+                &image,
+                ((x + 2) * TILE_SIZE) as u32,
+                ((y + 1) * TILE_SIZE) as u32,
+                TILE_SIZE as u32,
+                TILE_SIZE as u32,
+            )
+            .to_image();
+            assert!(mean_difference(tile.image()?, &source) < 10.0);
+        }
+
+        // The top of the pyramid is the downscaled blob.
+        let region = imageops::crop_imm(
+            &image,
+            (2 * TILE_SIZE) as u32,
+            TILE_SIZE as u32,
+            (4 * TILE_SIZE) as u32,
+            (4 * TILE_SIZE) as u32,
+        )
+        .to_image();
+        let t = TILE_SIZE as u32;
+        let expected = imageops::resize(&region, t, t, imageops::FilterType::Lanczos3);
+        let root = db.read((0, 0), 0)?;
+        assert!(mean_difference(root.image()?, &expected) < 10.0);
+
+        let patch =
+            db.read_region_scaled((0, 0), (4 * TILE_SIZE, 4 * TILE_SIZE), (t as u64, t as u64))?;
+        assert_eq!(patch.level(), 0);
+        assert!(mean_difference(patch.image()?, &expected) < 10.0);
+
+        let patch = db.read_region_scaled(
+            (TILE_SIZE, 0),
+            (2 * TILE_SIZE, 2 * TILE_SIZE),
+            (t as u64, t as u64),
+        )?;
+        assert_eq!(patch.level(), 1);
+        assert_eq!(patch.image()?.dimensions(), (t, t));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    /// All tiles as (id, x, y, level, data), ordered by id.
+    fn all_tiles(db: &Database) -> Result<Vec<(i64, i64, i64, i64, Vec<u8>)>> {
+        let statement = "SELECT id, x, y, level, jpeg from tiles ORDER BY id";
+        let mut statement = db.connection().prepare(statement)?;
+        let mut tiles = Vec::new();
+        while statement.next()? == sqlite::State::Row {
+            tiles.push((
+                statement.read::<i64, _>(0)?,
+                statement.read::<i64, _>(1)?,
+                statement.read::<i64, _>(2)?,
+                statement.read::<i64, _>(3)?,
+                statement.read::<Vec<u8>, _>(4)?,
+            ));
+        }
+        Ok(tiles)
+    }
+
+    #[test]
+    fn convert_independent_of_threads() -> Result<()> {
+        let dir = test_dir("convert_threads")?;
+        let mut results = Vec::new();
+        for threads in [1, 4] {
+            let db_path = dir.join(format!("slide_{}.sqlite", threads));
+            let mut config = test_config();
+            config.threads = threads;
+            convert_source(&test_slide().to_source(), db_path.clone(), &config)?;
+            results.push(all_tiles(&Database::open(&db_path)?)?);
+        }
+        assert!(!results[0].is_empty());
+        assert_eq!(results[0], results[1]);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn resume_each_stage() -> Result<()> {
+        let config = test_config();
+        let dir = test_dir("resume")?;
+        let slide_path = dir.join("slide.png");
+        test_slide().render().save(&slide_path)?;
+        convert(slide_path.clone(), dir.join("slide.sqlite"), &config)?;
+        let expected = all_tiles(&Database::open(&dir.join("slide.sqlite"))?)?;
+
+        for (stage, name) in STAGES.iter().enumerate() {
+            // Every interrupted conversion needs its own lockfile.
+            let stage_dir = dir.join(format!("stage_{}", stage));
+            std::fs::create_dir_all(&stage_dir)?;
+            let db_path = stage_dir.join("slide.sqlite");
+            let slide = open_slide(&slide_path)?;
+            let mut db = create_database(slide.as_ref(), Some(&slide_path), &db_path, &config)?;
+            let mut lock = LockFile::lock(&db_path, "Init")?;
+            read_slide(slide.as_ref(), &db, &config, &mut lock)?;
+            if stage == 0 {
+                // Only the first half of the tiles has been read.
+                db.connection().execute("DELETE FROM tiles WHERE x >= 4")?;
+            }
+            if stage >= 2 {
+                remove_islands(&db, &config, &mut lock)?;
+            }
+            if stage >= 3 {
+                crop(&mut db)?;
+            }
+            lock.state(name)?;
+            drop((db, lock));
+
+            resume(slide_path.clone(), db_path.clone(), &config)?;
+            assert!(!LockFile::exists(&db_path)?);
+            assert_eq!(all_tiles(&Database::open(&db_path)?)?, expected, "{}", name);
+        }
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn convert_native_levels() -> Result<()> {
+        let dir = test_dir("convert_native")?;
+        // Two blobs with a single tile island between them, inside the cropped area
+        let slide = SynthSlide::new(8 * TILE_SIZE, 8 * TILE_SIZE, 3)
+            .with_shape(tiles(0, 0, 6, 2))
+            .with_shape(tiles(0, 5, 6, 2))
+            .with_shape(tiles(3, 3, 1, 1));
+        // The exported pyramid keeps the island, converting it removes the island again.
+        let mut keep = test_config();
+        keep.island_tiles = 0;
+        let source_path = dir.join("source.sqlite");
+        convert_source(&slide.to_source(), source_path.clone(), &keep)?;
+        let tiff_path = dir.join("source.ome.tiff");
+        crate::export::export_tiff(&source_path, &tiff_path, false)?;
+
+        let mut config = test_config();
+        let resampled_path = dir.join("resampled.sqlite");
+        convert(tiff_path.clone(), resampled_path.clone(), &config)?;
+        config.native_levels = true;
+        let native_path = dir.join("native.sqlite");
+        convert(tiff_path, native_path.clone(), &config)?;
+
+        let native = Database::open(&native_path)?;
+        let resampled = Database::open(&resampled_path)?;
+        assert_eq!(native.tile_counts()?, resampled.tile_counts()?);
+        let max_level = native.levels() - 1;
+        assert!(native.read((3, 3), max_level)?.is_empty());
+        // Two levels below, the island is the last of 4x4 cells in the first tile.
+        let tile = native.read((0, 0), max_level - 2)?;
+        let cell = TILE_SIZE as u32 / 4;
+        let island = imageops::crop_imm(tile.image()?, 3 * cell, 3 * cell, cell, cell).to_image();
+        assert!(island.pixels().all(|p| p.0 == [255, 255, 255]));
+        for level in 0..max_level {
+            for pos in native.list_tiles(level)? {
+                let a = native.read(pos, level)?;
+                let b = resampled.read(pos, level)?;
+                let difference = mean_difference(a.image()?, b.image()?);
+                assert!(difference < 8.0, "{:?} {} {}", pos, level, difference);
+            }
+        }
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn convert_keeps_large_islands() -> Result<()> {
+        let dir = test_dir("convert_islands")?;
+        let db_path = dir.join("slide.sqlite");
+        let mut config = test_config();
+        config.island_size = 2;
+        config.island_tiles = 1;
+        convert_source(&test_slide().to_source(), db_path.clone(), &config)?;
+
+        // The 2x1 island has more than island_tiles tiles, so it is kept and widens the crop.
+        let db = Database::open(&db_path)?;
+        assert_eq!(db.levels(), 4);
+        assert_eq!(crop_offset(&db)?, (2 * TILE_SIZE, TILE_SIZE));
+        assert_eq!(db.tile_counts()?.last(), Some(&(3, 18)));
+        let positions = db.list_tiles(3)?;
+        assert!(positions.contains(&(4, 5)) && positions.contains(&(5, 5)));
+        assert!(!positions.contains(&(4, 4)));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn convert_image_file() -> Result<()> {
+        let dir = test_dir("convert_file")?;
+        let slide_path = dir.join("slide.png");
+        let db_path = dir.join("slide.sqlite");
+        SynthSlide::new(3 * TILE_SIZE, 2 * TILE_SIZE, 2)
+            .with_shape(tiles(0, 0, 3, 2))
+            .render()
+            .save(&slide_path)?;
+        convert(slide_path.clone(), db_path.clone(), &test_config())?;
+
+        let db = Database::open(&db_path)?;
+        assert_eq!(db.tile_counts()?, vec![(0, 1), (1, 2), (2, 6)]);
+        let metadata = db.read_metadata()?;
+        let canonical = std::fs::canonicalize(&slide_path)?;
+        assert_eq!(
+            metadata.get("slide_path"),
+            Some(&canonical.display().to_string())
+        );
+        assert_eq!(
+            db.read_properties()?
+                .get("image.format")
+                .map(|s| s.as_str()),
+            Some("png")
+        );
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn convert_tiff_passthrough() -> Result<()> {
+        let dir = test_dir("convert_passthrough")?;
+        let db_path = dir.join("slide.sqlite");
+        let tiff_path = dir.join("slide.ome.tiff");
+        let copy_path = dir.join("copy.sqlite");
+        convert_source(&test_slide().to_source(), db_path.clone(), &test_config())?;
+        crate::export::export_tiff(&db_path, &tiff_path, false)?;
+        let mut config = test_config();
+        config.passthrough = true;
+        convert(tiff_path, copy_path.clone(), &config)?;
+
+        let db = Database::open(&db_path)?;
+        let copy = Database::open(&copy_path)?;
+        assert_eq!(copy.tile_counts()?, db.tile_counts()?);
+        let level = db.levels() - 1;
+        for pos in db.list_tiles(level)? {
+            assert_eq!(copy.read_data(pos, level)?, db.read_data(pos, level)?);
+        }
+        let passthrough = |db: &Database| {
+            db.read_metadata()
+                .map(|m| m.get("jpeg_passthrough").cloned())
+        };
+        assert_eq!(passthrough(&copy)?, Some("true".to_owned()));
+        assert_eq!(passthrough(&db)?, None);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn convert_with_saturation_detector() -> Result<()> {
+        let dir = test_dir("convert_otsu")?;
+        let db_path = dir.join("slide.sqlite");
+        let mut config = test_config();
+        config.stain = Some(Stain::CD3);
+        assert_eq!(config.detector_kind(), DetectorKind::Combined);
+        config.tissue_detector = Some(DetectorKind::Otsu);
+        convert_source(&test_slide().to_source(), db_path.clone(), &config)?;
+
+        let db = Database::open(&db_path)?;
+        assert_eq!(db.tile_counts()?, vec![(0, 1), (1, 4), (2, 16)]);
+        let metadata = db.read_metadata()?;
+        assert_eq!(
+            metadata.get("tissue_detector").map(|s| s.as_str()),
+            Some("otsu")
+        );
+        assert_eq!(metadata.get("stain").map(|s| s.as_str()), Some("CD3"));
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
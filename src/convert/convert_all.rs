@@ -6,56 +6,96 @@ use anyhow::{bail, Result};
 use super::convert;
 use super::LockFile;
 
-use super::{Config, OpenSlide};
+use super::{is_slide_file, Config, ImageSlide};
 
 pub fn convert_all(
     input_path: PathBuf,
     output_path: PathBuf,
     config: &Config,
     force: bool,
+    images: bool,
 ) -> Result<()> {
     for entry in read_dir(&input_path)? {
         let entry = entry?;
         let path = entry.path();
         if path.is_file() {
-            match OpenSlide::open(&path) {
-                Ok(_) => {
-                    let mut output_file = match path.file_stem() {
-                        Some(s) => output_path.join(s),
-                        None => bail!("Invalid filename"),
-                    };
-                    output_file.set_extension("sqlite");
-                    if output_file.is_file() {
-                        if force {
-                            log::warn!("Overwriting {}", output_file.display());
-                            std::fs::remove_file(&output_file)?;
-                        } else {
-                            log::error!("{} already exists", output_file.display());
-                            bail!("{} already exists", output_file.display());
-                        }
+            if is_slide_file(&path) || (images && ImageSlide::is_supported(&path)) {
+                let mut output_file = match path.file_stem() {
+                    Some(s) => output_path.join(s),
+                    None => bail!("Invalid filename"),
+                };
+                output_file.set_extension("sqlite");
+                if output_file.is_file() {
+                    if force {
+                        log::warn!("Overwriting {}", output_file.display());
+                        std::fs::remove_file(&output_file)?;
+                    } else {
+                        log::error!("{} already exists", output_file.display());
+                        bail!("{} already exists", output_file.display());
                     }
-                    if LockFile::exists(&output_file)? {
-                        if force {
-                            log::warn!("Ignoring lockfile");
-                        } else {
-                            log::debug!("LockFile exists. Skipping.");
-                            return Ok(());
-                        }
-                    }
-                    log::debug!("Converting {} to {}", path.display(), output_file.display());
-                    convert(path.clone(), output_file, config)?;
                 }
-                Err(_) => {
-                    log::debug!("{} is not a slide file. Ignoring.", path.display());
+                if LockFile::exists(&output_file)? {
+                    if force {
+                        log::warn!("Ignoring lockfile");
+                    } else {
+                        log::debug!("LockFile exists. Skipping.");
+                        return Ok(());
+                    }
                 }
+                log::debug!("Converting {} to {}", path.display(), output_file.display());
+                convert(path.clone(), output_file, config)?;
+            } else {
+                log::debug!("{} is not a slide file. Ignoring.", path.display());
             }
         }
         if path.is_dir() {
             let relative_path = path.strip_prefix(&input_path)?;
             let new_dir = output_path.join(relative_path);
             std::fs::create_dir_all(&new_dir)?;
-            convert_all(path, new_dir, config, force)?;
+            convert_all(path, new_dir, config, force, images)?;
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::convert_all;
+    use crate::convert::{Config, Shape, SynthSlide};
+    use anyhow::Result;
+
+    #[test]
+    fn images_only_on_request() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("pamly_convert_all_{}", std::process::id()));
+        if dir.is_dir() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        let (input, output) = (dir.join("in"), dir.join("out"));
+        std::fs::create_dir_all(&input)?;
+        std::fs::create_dir_all(&output)?;
+        SynthSlide::new(64, 64, 1)
+            .with_shape(Shape::Rect {
+                x: 0,
+                y: 0,
+                width: 32,
+                height: 32,
+            })
+            .render()
+            .save(input.join("photo.png"))?;
+        let config = Config {
+            tile_size: 32,
+            edge_detect_size: 32,
+            threads: 1,
+            ..Config::default()
+        };
+
+        convert_all(input.clone(), output.clone(), &config, false, false)?;
+        assert!(!output.join("photo.sqlite").exists());
+
+        convert_all(input, output.clone(), &config, false, true)?;
+        assert!(output.join("photo.sqlite").is_file());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
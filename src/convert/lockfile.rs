@@ -2,7 +2,7 @@ use anyhow::{bail, Result};
 
 use serde::{Deserialize, Serialize};
 use std::fs::{self, File};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LockFile {
@@ -14,10 +14,10 @@ pub struct LockFile {
     pub error: Option<String>,
 }
 
-fn get_lock_path(path: &PathBuf) -> Result<PathBuf> {
+fn get_lock_path(path: &Path) -> Result<PathBuf> {
     let filename = "pamly.lock";
     let base_path = if path.is_dir() {
-        path.clone()
+        path.to_path_buf()
     } else {
         match path.parent() {
             Some(p) => p.to_owned(),
@@ -29,12 +29,12 @@ fn get_lock_path(path: &PathBuf) -> Result<PathBuf> {
 }
 
 impl LockFile {
-    pub fn exists(path: &PathBuf) -> Result<bool> {
+    pub fn exists(path: &Path) -> Result<bool> {
         let lock_path = get_lock_path(path)?;
         Ok(lock_path.is_file())
     }
 
-    pub fn find(path: &PathBuf) -> Result<Option<LockFile>> {
+    pub fn find(path: &Path) -> Result<Option<LockFile>> {
         let lock_path = get_lock_path(path)?;
         if !lock_path.is_file() {
             return Ok(None);
@@ -44,7 +44,7 @@ impl LockFile {
         lock.path = Some(lock_path);
         Ok(Some(lock))
     }
-    pub fn lock(path: &PathBuf, state: &str) -> Result<LockFile> {
+    pub fn lock(path: &Path, state: &str) -> Result<LockFile> {
         let lock_path = get_lock_path(path)?;
         let lock = LockFile {
             path: Some(lock_path),
@@ -61,7 +61,7 @@ impl LockFile {
             Some(p) => p,
             None => bail!("no path"),
         };
-        fs::remove_file(&path)?;
+        fs::remove_file(path)?;
         Ok(())
     }
 
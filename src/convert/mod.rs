@@ -1,17 +1,31 @@
 mod config;
 pub use config::Config;
 
-mod openslide;
-pub use openslide::OpenSlide;
+mod sources;
+#[cfg(feature = "openslide")]
+pub use sources::OpenSlide;
+pub use sources::{
+    is_slide_file, open_passthrough, open_slide, ImageSlide, SlideSource, TiffSlide,
+};
 
 mod actions;
 pub use actions::*;
 
+mod tissue;
+pub use tissue::{
+    tissue_detector, CombinedDetector, DetectorKind, EdgeDetector, OtsuDetector, TissueDetector,
+    OVERVIEW_SIZE,
+};
+
 mod lockfile;
 pub use lockfile::LockFile;
 
+#[allow(clippy::module_inception)]
 mod convert;
-pub use convert::convert;
+pub use convert::{convert, convert_source, resume};
 
 mod convert_all;
 pub use convert_all::convert_all;
+
+mod synth;
+pub use synth::{Shape, SynthSlide, SYNTH_PPM};
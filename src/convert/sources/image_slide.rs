@@ -0,0 +1,121 @@
+use anyhow::{bail, Result};
+use image::{imageops, ImageBuffer, ImageFormat, ImageReader, Rgb, RgbImage};
+use std::{collections::HashMap, path::PathBuf};
+
+use super::SlideSource;
+
+/// A slide stored as a plain image (png, jpeg, tiff, ...), read completely into memory.
+pub struct ImageSlide {
+    image: RgbImage,
+    format: String,
+    resolution: Option<(u64, u64)>,
+}
+
+impl ImageSlide {
+    pub fn is_supported(path: &PathBuf) -> bool {
+        match ImageFormat::from_path(path) {
+            Ok(format) => format.reading_enabled(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn open(path: &PathBuf) -> Result<ImageSlide> {
+        let format = match ImageFormat::from_path(path) {
+            Ok(f) => f,
+            Err(_) => bail!("Unknown image format {}", path.display()),
+        };
+        log::debug!("Reading {} into memory", path.display());
+        // image limits allocations to 512 MiB by default, which slide scale images exceed.
+        // The decoded image is kept in memory as a whole anyway, so no limits are set.
+        let mut reader = ImageReader::open(path)?.with_guessed_format()?;
+        reader.no_limits();
+        let image = reader.decode()?.to_rgb8();
+        Ok(ImageSlide {
+            image,
+            format: format!("{:?}", format).to_lowercase(),
+            resolution: None,
+        })
+    }
+
+    pub fn new(image: RgbImage) -> ImageSlide {
+        ImageSlide {
+            image,
+            format: "memory".to_owned(),
+            resolution: None,
+        }
+    }
+
+    /// Sets the resolution in pixels per meter, plain images do not store it reliably.
+    pub fn with_resolution(mut self, x_ppm: u64, y_ppm: u64) -> ImageSlide {
+        self.resolution = Some((x_ppm, y_ppm));
+        self
+    }
+
+    pub fn image(&self) -> &RgbImage {
+        &self.image
+    }
+}
+
+impl SlideSource for ImageSlide {
+    fn vendor(&self) -> String {
+        format!("image ({})", self.format)
+    }
+
+    fn size(&self) -> (u64, u64) {
+        (self.image.width() as u64, self.image.height() as u64)
+    }
+
+    fn resolution(&self) -> Result<(u64, u64)> {
+        match self.resolution {
+            Some(r) => Ok(r),
+            None => bail!("Could not read resolution from image"),
+        }
+    }
+
+    fn read_region(&self, x: i64, y: i64, width: i64, height: i64) -> Result<RgbImage> {
+        if width < 0 || height < 0 {
+            bail!("Invalid region size {}x{}", width, height);
+        }
+        let white = Rgb([255, 255, 255]);
+        let mut region: RgbImage = ImageBuffer::from_pixel(width as u32, height as u32, white);
+        let (w, h) = self.size();
+        let x0 = x.clamp(0, w as i64);
+        let y0 = y.clamp(0, h as i64);
+        let x1 = (x + width).clamp(0, w as i64);
+        let y1 = (y + height).clamp(0, h as i64);
+        if x1 > x0 && y1 > y0 {
+            let view = imageops::crop_imm(
+                &self.image,
+                x0 as u32,
+                y0 as u32,
+                (x1 - x0) as u32,
+                (y1 - y0) as u32,
+            );
+            imageops::replace(&mut region, &*view, x0 - x, y0 - y);
+        }
+        Ok(region)
+    }
+
+    fn properties(&self) -> Result<HashMap<String, String>> {
+        let mut properties = HashMap::new();
+        properties.insert("image.format".to_owned(), self.format.clone());
+        Ok(properties)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ImageSlide, Result, SlideSource};
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn read_region_outside() -> Result<()> {
+        let slide = ImageSlide::new(RgbImage::from_pixel(4, 4, Rgb([0, 0, 0])));
+        let region = slide.read_region(2, -1, 4, 4)?;
+        assert_eq!(region.dimensions(), (4, 4));
+        assert_eq!(region.get_pixel(0, 0), &Rgb([255, 255, 255]));
+        assert_eq!(region.get_pixel(0, 1), &Rgb([0, 0, 0]));
+        assert_eq!(region.get_pixel(2, 1), &Rgb([255, 255, 255]));
+        Ok(())
+    }
+}
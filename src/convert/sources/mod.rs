@@ -0,0 +1,136 @@
+use anyhow::{bail, Result};
+use image::{imageops, RgbImage};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+mod image_slide;
+pub use image_slide::ImageSlide;
+
+mod tiff_slide;
+pub use tiff_slide::TiffSlide;
+
+#[cfg(feature = "openslide")]
+mod openslide;
+#[cfg(feature = "openslide")]
+pub use openslide::OpenSlide;
+
+#[cfg(feature = "openslide")]
+mod passthrough;
+#[cfg(feature = "openslide")]
+use passthrough::PassthroughSlide;
+
+/// A whole slide image the converter can read from.
+/// Coordinates are given in pixels of the full resolution level.
+pub trait SlideSource: Send + Sync {
+    /// Name of the backend or scanner vendor
+    fn vendor(&self) -> String;
+    /// Size (w x h) of the full resolution level
+    fn size(&self) -> (u64, u64);
+    /// Resolution in pixels per meter
+    fn resolution(&self) -> Result<(u64, u64)>;
+    fn read_region(&self, x: i64, y: i64, width: i64, height: i64) -> Result<RgbImage>;
+    /// Vendor specific properties of the slide
+    fn properties(&self) -> Result<HashMap<String, String>>;
+
+    fn level_count(&self) -> Result<i32> {
+        Ok(1)
+    }
+    fn level_dimensions(&self, level: i32) -> Result<(u64, u64)> {
+        match level {
+            0 => Ok(self.size()),
+            _ => bail!("Level {} does not exist", level),
+        }
+    }
+    fn level_downsample(&self, level: i32) -> Result<f64> {
+        match level {
+            0 => Ok(1.0),
+            _ => bail!("Level {} does not exist", level),
+        }
+    }
+    /// Reads a region of a pyramid level, x and y are given in level 0 coordinates.
+    fn read_region_level(
+        &self,
+        x: i64,
+        y: i64,
+        level: i32,
+        width: i64,
+        height: i64,
+    ) -> Result<RgbImage> {
+        match level {
+            0 => self.read_region(x, y, width, height),
+            _ => bail!("Level {} does not exist", level),
+        }
+    }
+
+    /// Reads the JPEG data of the full resolution tile at (x, y) without decoding it.
+    /// Only available if the slide stores `size` x `size` JPEG tiles aligned with (x, y).
+    fn read_raw_tile(&self, _x: i64, _y: i64, _size: u64) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Names of the associated images, e.g. label, macro or thumbnail.
+    fn associated_image_names(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+    fn read_associated_image(&self, name: &str) -> Result<RgbImage> {
+        bail!("No associated image {}", name)
+    }
+
+    /// Reads the lowest resolution level, scaled so that the longer side is `size` pixels.
+    fn thumbnail(&self, size: u64) -> Result<RgbImage> {
+        let level = self.level_count()? - 1;
+        let (w, h) = self.level_dimensions(level)?;
+        log::debug!("Reading thumbnail from level {} ({}x{})", level, w, h);
+        let image = self.read_region_level(0, 0, level, w as i64, h as i64)?;
+
+        let scaling = size as f64 / std::cmp::max(w, h) as f64;
+        let tw = std::cmp::max((w as f64 * scaling).round() as u32, 1);
+        let th = std::cmp::max((h as f64 * scaling).round() as u32, 1);
+        let scaled = imageops::resize(&image, tw, th, imageops::FilterType::Lanczos3);
+        Ok(scaled)
+    }
+}
+
+/// Checks whether one of the whole slide backends can read the file, without reading it.
+/// Plain images are not considered slides, use `ImageSlide::is_supported` for those.
+pub fn is_slide_file(path: &Path) -> bool {
+    #[cfg(feature = "openslide")]
+    if OpenSlide::detect(path) {
+        return true;
+    }
+    TiffSlide::detect(path)
+}
+
+/// Opens a slide with the first backend that supports it.
+pub fn open_slide(path: &PathBuf) -> Result<Box<dyn SlideSource>> {
+    #[cfg(feature = "openslide")]
+    if OpenSlide::detect(path) {
+        return Ok(Box::new(OpenSlide::open(path)?));
+    }
+    if TiffSlide::detect(path) {
+        return Ok(Box::new(TiffSlide::open(path)?));
+    }
+    if ImageSlide::is_supported(path) {
+        return Ok(Box::new(ImageSlide::open(path)?));
+    }
+    bail!("{} is not a supported slide file", path.display())
+}
+
+/// Opens a slide like `open_slide`, but with access to the compressed tiles of TIFF files.
+/// OpenSlide still reads the regions, the built-in reader only provides the JPEG tiles.
+pub fn open_passthrough(path: &PathBuf) -> Result<Box<dyn SlideSource>> {
+    #[cfg(feature = "openslide")]
+    if OpenSlide::detect(path) && TiffSlide::detect(path) {
+        let slide = OpenSlide::open(path)?;
+        let tiff = TiffSlide::open(path)?;
+        // The first TIFF image has to be the full resolution level of the slide.
+        if tiff.size() == slide.size() {
+            return Ok(Box::new(PassthroughSlide::new(slide, tiff)));
+        }
+        log::warn!("Tiles of {} can not be passed through", path.display());
+        return Ok(Box::new(slide));
+    }
+    open_slide(path)
+}
@@ -4,7 +4,9 @@ use std::{self, collections::HashMap, ffi, str};
 
 use image::{ImageBuffer, RgbImage};
 
-use std::path::PathBuf;
+use super::SlideSource;
+
+use std::path::{Path, PathBuf};
 
 const LEVEL: i32 = 0;
 const X_RESOLUTION_KEY: &'static str = "openslide.mpp-x";
@@ -17,6 +19,7 @@ extern "C" {
     fn openslide_detect_vendor(filename: *const libc::c_char) -> *const libc::c_char;
     fn openslide_open(filename: *const libc::c_char) -> *const OpenSlideT;
     fn openslide_close(osr: *const OpenSlideT) -> libc::c_void;
+    fn openslide_get_level_count(osr: *const OpenSlideT) -> i32;
     fn openslide_get_level_dimensions(
         osr: *const OpenSlideT,
         level: i32,
@@ -38,6 +41,18 @@ extern "C" {
         osr: *const OpenSlideT,
         name: *const libc::c_char,
     ) -> *const libc::c_char;
+    fn openslide_get_associated_image_names(osr: *const OpenSlideT) -> *const *const libc::c_char;
+    fn openslide_get_associated_image_dimensions(
+        osr: *const OpenSlideT,
+        name: *const libc::c_char,
+        w: *mut i64,
+        h: *mut i64,
+    ) -> libc::c_void;
+    fn openslide_read_associated_image(
+        osr: *const OpenSlideT,
+        name: *const libc::c_char,
+        dest: *mut u32,
+    ) -> libc::c_void;
 }
 
 /// Quickly determine whether a whole slide image is recognized.
@@ -65,6 +80,15 @@ unsafe fn close(osr: *const OpenSlideT) {
     openslide_close(osr); // This is unsafe
 }
 
+/// Get the number of levels in the whole slide image.
+unsafe fn get_level_count(osr: *const OpenSlideT) -> Result<i32> {
+    let count = openslide_get_level_count(osr); // This is unsafe
+    if count < 1 {
+        bail!("Failed to get level count");
+    }
+    Ok(count)
+}
+
 /// Get the dimensions of a level.
 unsafe fn get_level_dimensions(osr: *const OpenSlideT, level: i32) -> Result<(i64, i64)> {
     let mut width: i64 = 0;
@@ -128,6 +152,58 @@ unsafe fn get_property_value(osr: *const OpenSlideT, name: &str) -> Result<Strin
     Ok(value)
 }
 
+/// Get the NULL-terminated array of associated image names.
+unsafe fn get_associated_image_names(osr: *const OpenSlideT) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut loc = openslide_get_associated_image_names(osr);
+    while !(*loc).is_null() {
+        let name = ffi::CStr::from_ptr(*loc).to_string_lossy().into_owned();
+        names.push(name);
+        loc = loc.offset(1);
+    }
+    Ok(names)
+}
+
+/// Copy pre-multiplied ARGB data from an associated image.
+unsafe fn read_associated_image(
+    osr: *const OpenSlideT,
+    name: &str,
+) -> Result<(Vec<u32>, i64, i64)> {
+    let c_name = ffi::CString::new(name)?;
+    let mut w: i64 = 0;
+    let mut h: i64 = 0;
+    openslide_get_associated_image_dimensions(osr, c_name.as_ptr(), &mut w, &mut h); // This is unsafe
+    if w < 0 || h < 0 {
+        bail!("Failed to get dimensions of associated image {}", name);
+    }
+    let mut buffer: Vec<u32> = Vec::with_capacity((h * w) as usize);
+    let p_buffer = buffer.as_mut_ptr();
+    openslide_read_associated_image(osr, c_name.as_ptr(), p_buffer); // This is unsafe
+    buffer.set_len((h * w) as usize);
+    Ok((buffer, w, h))
+}
+
+/// Converts pre-multiplied ARGB pixels to RGB, transparent pixels become white.
+fn to_rgb_image(data: &[u32], width: i64, height: i64) -> Result<RgbImage> {
+    let mut rgb: Vec<u8> = Vec::with_capacity(data.len() * 4);
+    for value in data.iter() {
+        let [a, r, g, b] = value.to_be_bytes();
+        match a {
+            0 => rgb.extend_from_slice(&[0xFF, 0xFF, 0xFF]),
+            0xFF => rgb.extend_from_slice(&[r, g, b]),
+            _ => {
+                let unmultiply = |c: u8| (0xFF * c as u32 / a as u32) as u8;
+                rgb.extend_from_slice(&[unmultiply(r), unmultiply(g), unmultiply(b)])
+            }
+        };
+    }
+    let image_buffer: RgbImage = match ImageBuffer::from_raw(width as u32, height as u32, rgb) {
+        Some(image) => image,
+        None => bail!("Could not convert tile to image buffer"),
+    };
+    Ok(image_buffer)
+}
+
 #[derive(Debug, Clone)]
 pub struct OpenSlide {
     osr: *const OpenSlideT,
@@ -140,6 +216,14 @@ unsafe impl Send for OpenSlide {}
 unsafe impl Sync for OpenSlide {}
 
 impl OpenSlide {
+    /// Checks whether openslide recognizes the file.
+    pub fn detect(path: &Path) -> bool {
+        match path.to_str() {
+            Some(p) => detect_vendor(p).is_ok(),
+            None => false,
+        }
+    }
+
     pub fn open(path: &PathBuf) -> Result<OpenSlide> {
         let raw_path = match path.to_str() {
             Some(p) => p,
@@ -170,25 +254,56 @@ impl OpenSlide {
         })
     }
     pub fn read_region(&self, x: i64, y: i64, width: i64, height: i64) -> Result<RgbImage> {
-        let data = match unsafe { read_region(self.osr, x, y, LEVEL, width, height) } {
+        self.read_region_level(x, y, LEVEL, width, height)
+    }
+
+    /// Reads a region of a pyramid level, x and y are given in level 0 coordinates.
+    pub fn read_region_level(
+        &self,
+        x: i64,
+        y: i64,
+        level: i32,
+        width: i64,
+        height: i64,
+    ) -> Result<RgbImage> {
+        let data = match unsafe { read_region(self.osr, x, y, level, width, height) } {
             Ok(r) => r,
             Err(_) => bail!("Call to read_region failed"),
         };
-        let mut rgb: Vec<u8> = Vec::with_capacity(data.len() * 4);
-        for value in data.iter() {
-            let [a, r, g, b] = value.to_be_bytes();
-            match a {
-                0 => rgb.extend_from_slice(&[0xFF, 0xFF, 0xFF]),
-                0xFF => rgb.extend_from_slice(&[r, g, b]),
-                _ => rgb.extend_from_slice(&[0xFF * r / a, 0xFF * g / a, 0xFF * b / a]),
-            };
+        to_rgb_image(&data, width, height)
+    }
+
+    /// Names of the associated images, e.g. label, macro or thumbnail.
+    pub fn associated_image_names(&self) -> Result<Vec<String>> {
+        match unsafe { get_associated_image_names(self.osr) } {
+            Ok(names) => Ok(names),
+            Err(_) => bail!("Failed to read associated image names."),
         }
-        let image_buffer: RgbImage = match ImageBuffer::from_raw(width as u32, height as u32, rgb) {
-            Some(image) => image,
-            None => bail!("Could not convert tile to image buffer"),
-        };
+    }
 
-        Ok(image_buffer)
+    pub fn read_associated_image(&self, name: &str) -> Result<RgbImage> {
+        let (data, width, height) = unsafe { read_associated_image(self.osr, name) }?;
+        to_rgb_image(&data, width, height)
+    }
+
+    pub fn level_count(&self) -> Result<i32> {
+        unsafe { get_level_count(self.osr) }
+    }
+
+    pub fn level_dimensions(&self, level: i32) -> Result<(u64, u64)> {
+        let (w, h) = unsafe { get_level_dimensions(self.osr, level) }?;
+        if w < 0 || h < 0 {
+            bail!("Failed to read dimensions of level {}", level);
+        }
+        Ok((w as u64, h as u64))
+    }
+
+    pub fn level_downsample(&self, level: i32) -> Result<f64> {
+        let downsample = unsafe { get_level_downsample(self.osr, level) }?;
+        if downsample < 0.0 {
+            bail!("Failed to read downsample factor of level {}", level);
+        }
+        Ok(downsample)
     }
 
     pub fn get_metadata(&self) -> Result<HashMap<String, String>> {
@@ -243,3 +358,46 @@ impl Drop for OpenSlide {
         unsafe { close(self.osr) };
     }
 }
+
+impl SlideSource for OpenSlide {
+    fn vendor(&self) -> String {
+        self.vendor.clone()
+    }
+    fn size(&self) -> (u64, u64) {
+        OpenSlide::size(self)
+    }
+    fn resolution(&self) -> Result<(u64, u64)> {
+        self.get_resolution()
+    }
+    fn read_region(&self, x: i64, y: i64, width: i64, height: i64) -> Result<RgbImage> {
+        OpenSlide::read_region(self, x, y, width, height)
+    }
+    fn properties(&self) -> Result<HashMap<String, String>> {
+        self.get_metadata()
+    }
+    fn level_count(&self) -> Result<i32> {
+        OpenSlide::level_count(self)
+    }
+    fn level_dimensions(&self, level: i32) -> Result<(u64, u64)> {
+        OpenSlide::level_dimensions(self, level)
+    }
+    fn level_downsample(&self, level: i32) -> Result<f64> {
+        OpenSlide::level_downsample(self, level)
+    }
+    fn read_region_level(
+        &self,
+        x: i64,
+        y: i64,
+        level: i32,
+        width: i64,
+        height: i64,
+    ) -> Result<RgbImage> {
+        OpenSlide::read_region_level(self, x, y, level, width, height)
+    }
+    fn associated_image_names(&self) -> Result<Vec<String>> {
+        OpenSlide::associated_image_names(self)
+    }
+    fn read_associated_image(&self, name: &str) -> Result<RgbImage> {
+        OpenSlide::read_associated_image(self, name)
+    }
+}
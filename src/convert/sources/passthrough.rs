@@ -0,0 +1,63 @@
+use anyhow::Result;
+use image::RgbImage;
+use std::collections::HashMap;
+
+use super::{OpenSlide, SlideSource, TiffSlide};
+
+/// A slide read with OpenSlide, whose compressed tiles are taken from the built-in TIFF reader.
+pub struct PassthroughSlide {
+    slide: OpenSlide,
+    tiff: TiffSlide,
+}
+
+impl PassthroughSlide {
+    pub fn new(slide: OpenSlide, tiff: TiffSlide) -> PassthroughSlide {
+        PassthroughSlide { slide, tiff }
+    }
+}
+
+impl SlideSource for PassthroughSlide {
+    fn vendor(&self) -> String {
+        self.slide.vendor()
+    }
+    fn size(&self) -> (u64, u64) {
+        self.slide.size()
+    }
+    fn resolution(&self) -> Result<(u64, u64)> {
+        self.slide.resolution()
+    }
+    fn read_region(&self, x: i64, y: i64, width: i64, height: i64) -> Result<RgbImage> {
+        self.slide.read_region(x, y, width, height)
+    }
+    fn properties(&self) -> Result<HashMap<String, String>> {
+        self.slide.properties()
+    }
+    fn level_count(&self) -> Result<i32> {
+        self.slide.level_count()
+    }
+    fn level_dimensions(&self, level: i32) -> Result<(u64, u64)> {
+        self.slide.level_dimensions(level)
+    }
+    fn level_downsample(&self, level: i32) -> Result<f64> {
+        self.slide.level_downsample(level)
+    }
+    fn read_region_level(
+        &self,
+        x: i64,
+        y: i64,
+        level: i32,
+        width: i64,
+        height: i64,
+    ) -> Result<RgbImage> {
+        self.slide.read_region_level(x, y, level, width, height)
+    }
+    fn read_raw_tile(&self, x: i64, y: i64, size: u64) -> Result<Option<Vec<u8>>> {
+        self.tiff.read_raw_tile(x, y, size)
+    }
+    fn associated_image_names(&self) -> Result<Vec<String>> {
+        self.slide.associated_image_names()
+    }
+    fn read_associated_image(&self, name: &str) -> Result<RgbImage> {
+        self.slide.read_associated_image(name)
+    }
+}
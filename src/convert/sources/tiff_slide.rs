@@ -0,0 +1,657 @@
+use anyhow::{bail, Result};
+use image::{imageops, ImageBuffer, Rgb, RgbImage};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tiff::decoder::{ifd::Value, ChunkType, Decoder, DecodingResult};
+use tiff::tags::{CompressionMethod, IfdPointer, Tag};
+use tiff::ColorType;
+
+use super::SlideSource;
+
+/// Tiles may be stored uncompressed or with one of these compressions.
+const COMPRESSIONS: [CompressionMethod; 5] = [
+    CompressionMethod::None,
+    CompressionMethod::LZW,
+    CompressionMethod::Deflate,
+    CompressionMethod::OldDeflate,
+    CompressionMethod::ModernJPEG,
+];
+
+/// Adobe APP14 segment with transform 0, marks the components of a JPEG stream as RGB.
+const ADOBE_RGB: [u8; 16] = [
+    0xFF, 0xEE, 0x00, 0x0E, b'A', b'd', b'o', b'b', b'e', 0x00, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Reads a TIFF file as if `ifd` were its first directory.
+/// The decoder only follows the main chain of directories, this also gives it access to SubIFDs.
+struct IfdReader {
+    file: BufReader<File>,
+    header: Vec<u8>,
+    position: u64,
+}
+
+impl IfdReader {
+    fn open(path: &Path, ifd: IfdPointer) -> Result<IfdReader> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut header = vec![0; 4];
+        file.read_exact(&mut header)?;
+        let big_endian = header[0] == b'M';
+        let big = header[2..4] == [0, 43] || header[2..4] == [43, 0];
+        match (big, big_endian) {
+            (true, true) => header.extend([0, 8, 0, 0].iter().chain(&ifd.0.to_be_bytes())),
+            (true, false) => header.extend([8, 0, 0, 0].iter().chain(&ifd.0.to_le_bytes())),
+            (false, true) => header.extend((ifd.0 as u32).to_be_bytes()),
+            (false, false) => header.extend((ifd.0 as u32).to_le_bytes()),
+        }
+        file.seek(SeekFrom::Start(0))?;
+        Ok(IfdReader {
+            file,
+            header,
+            position: 0,
+        })
+    }
+}
+
+impl Read for IfdReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.file.read(buf)?;
+        for (i, byte) in buf[..n].iter_mut().enumerate() {
+            if let Some(h) = self.header.get(self.position as usize + i) {
+                *byte = *h;
+            }
+        }
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for IfdReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = self.file.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+type TiffDecoder = Decoder<IfdReader>;
+
+/// A directory of the file with its image size.
+#[derive(Debug, Clone, Copy)]
+struct Directory {
+    ifd: IfdPointer,
+    width: u64,
+    height: u64,
+    tiled: bool,
+}
+
+/// A pyramid level, decoders are opened on demand and reused, so threads can read in parallel.
+struct Level {
+    directory: Directory,
+    downsample: f64,
+    decoders: Mutex<Vec<TiffDecoder>>,
+}
+
+/// The JPEG compressed tiles of the full resolution level, read without decoding.
+struct JpegTiles {
+    file: Mutex<File>,
+    tile_width: u64,
+    tile_height: u64,
+    columns: u64,
+    offsets: Vec<u64>,
+    byte_counts: Vec<u64>,
+    /// Quantization and Huffman tables shared by all tiles
+    tables: Option<Vec<u8>>,
+    /// Tiles are stored as RGB instead of YCbCr
+    rgb: bool,
+}
+
+impl JpegTiles {
+    fn open(path: &Path, directory: &Directory) -> Result<Option<JpegTiles>> {
+        let mut decoder = open_decoder(path, directory.ifd)?;
+        let compression = decoder.find_tag_unsigned::<u16>(Tag::Compression)?;
+        let photometric = decoder.find_tag_unsigned::<u16>(Tag::PhotometricInterpretation)?;
+        let samples = decoder.find_tag_unsigned::<u16>(Tag::SamplesPerPixel)?;
+        let planar = decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)?;
+        if compression != Some(CompressionMethod::ModernJPEG.to_u16())
+            || samples != Some(3)
+            || planar.unwrap_or(1) != 1
+        {
+            return Ok(None);
+        }
+        let rgb = match photometric {
+            Some(2) => true,
+            Some(6) => false,
+            _ => return Ok(None),
+        };
+        let (tile_width, tile_height) = decoder.chunk_dimensions();
+        let (tile_width, tile_height) = (tile_width as u64, tile_height as u64);
+        let tables = match decoder.find_tag(Tag::JPEGTables)? {
+            Some(value) => Some(value.into_u8_vec()?),
+            None => None,
+        };
+        Ok(Some(JpegTiles {
+            file: Mutex::new(File::open(path)?),
+            tile_width,
+            tile_height,
+            columns: directory.width.div_ceil(tile_width),
+            offsets: decoder.get_tag_u64_vec(Tag::TileOffsets)?,
+            byte_counts: decoder.get_tag_u64_vec(Tag::TileByteCounts)?,
+            tables,
+            rgb,
+        }))
+    }
+
+    fn read(&self, column: u64, row: u64) -> Result<Option<Vec<u8>>> {
+        let index = (row * self.columns + column) as usize;
+        let (Some(offset), Some(count)) = (self.offsets.get(index), self.byte_counts.get(index))
+        else {
+            return Ok(None);
+        };
+        let mut data = vec![0; *count as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(*offset))?;
+            file.read_exact(&mut data)?;
+        }
+        if !data.starts_with(&[0xFF, 0xD8]) {
+            return Ok(None);
+        }
+        Ok(Some(complete_jpeg(&data, self.tables.as_deref(), self.rgb)))
+    }
+}
+
+/// Turns a tile into a standalone JPEG stream, the shared tables are inserted after
+/// the start of image marker and RGB tiles are marked with an Adobe segment.
+fn complete_jpeg(tile: &[u8], tables: Option<&[u8]>, rgb: bool) -> Vec<u8> {
+    let mut data = Vec::with_capacity(tile.len() + tables.map_or(0, |t| t.len()) + 16);
+    data.extend(&tile[..2]);
+    if rgb {
+        data.extend(ADOBE_RGB);
+    }
+    // The tables are a stream of their own, without start and end of image.
+    if let Some(tables) = tables.filter(|t| t.len() > 4) {
+        data.extend(&tables[2..tables.len() - 2]);
+    }
+    data.extend(&tile[2..]);
+    data
+}
+
+/// A tiled, pyramidal TIFF or BigTIFF read without OpenSlide.
+/// The largest tiled image is the full resolution, all tiled images and SubIFDs
+/// with the same aspect ratio are its pyramid levels.
+pub struct TiffSlide {
+    path: PathBuf,
+    levels: Vec<Level>,
+    associated: Vec<(String, Directory)>,
+    properties: HashMap<String, String>,
+    resolution: Option<(u64, u64)>,
+    jpeg: Option<JpegTiles>,
+}
+
+fn open_decoder(path: &Path, ifd: IfdPointer) -> Result<TiffDecoder> {
+    let decoder = Decoder::new(IfdReader::open(path, ifd)?)?;
+    Ok(decoder)
+}
+
+/// Converts decoded samples to RGB, JPEG tiles are returned as YCbCr.
+fn to_rgb(color: ColorType, samples: Vec<u8>, width: u32, height: u32) -> Result<RgbImage> {
+    let pixels = (width * height) as usize;
+    let channels = match color {
+        ColorType::RGB(8) | ColorType::YCbCr(8) => 3,
+        ColorType::RGBA(8) => 4,
+        ColorType::Gray(8) => 1,
+        c => bail!("Unsupported color type {:?}", c),
+    };
+    if samples.len() < pixels * channels {
+        bail!(
+            "Expected {} samples, got {}",
+            pixels * channels,
+            samples.len()
+        );
+    }
+    let mut rgb = Vec::with_capacity(pixels * 3);
+    for p in samples.chunks_exact(channels).take(pixels) {
+        match color {
+            ColorType::YCbCr(_) => {
+                let (y, cb, cr) = (p[0] as f32, p[1] as f32 - 128.0, p[2] as f32 - 128.0);
+                rgb.push((y + 1.402 * cr).round().clamp(0.0, 255.0) as u8);
+                rgb.push(
+                    (y - 0.344136 * cb - 0.714136 * cr)
+                        .round()
+                        .clamp(0.0, 255.0) as u8,
+                );
+                rgb.push((y + 1.772 * cb).round().clamp(0.0, 255.0) as u8);
+            }
+            ColorType::Gray(_) => rgb.extend([p[0], p[0], p[0]]),
+            _ => rgb.extend(&p[..3]),
+        }
+    }
+    match RgbImage::from_raw(width, height, rgb) {
+        Some(image) => Ok(image),
+        None => bail!("Invalid image size {}x{}", width, height),
+    }
+}
+
+/// Pixels per meter from a resolution tag value.
+fn ppm(value: Option<Value>, unit: Option<u16>) -> Option<u64> {
+    let per_unit = match value? {
+        Value::Rational(n, d) if d > 0 => n as f64 / d as f64,
+        Value::Float(v) => v as f64,
+        Value::Double(v) => v,
+        _ => return None,
+    };
+    let ppm = match unit {
+        Some(3) => per_unit * 100.0,
+        Some(2) | None => per_unit / 0.0254,
+        _ => return None,
+    };
+    (ppm > 1.0).then_some(ppm.round() as u64)
+}
+
+/// Micrometres per pixel from an Aperio (`MPP = 0.25`) or OME-XML (`PhysicalSizeX="0.25"`) description.
+fn description_mpp(description: &str, key: &str) -> Option<f64> {
+    let start = description.find(key)? + key.len();
+    let value: String = description[start..]
+        .trim_start_matches([' ', '=', '"'])
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    value.parse().ok().filter(|mpp: &f64| *mpp > 0.0)
+}
+
+impl TiffSlide {
+    /// Checks whether the file is a TIFF with a tiled first image in a supported compression.
+    pub fn detect(path: &Path) -> bool {
+        let Ok(file) = File::open(path) else {
+            return false;
+        };
+        let Ok(mut decoder) = Decoder::new(BufReader::new(file)) else {
+            return false;
+        };
+        let compression = decoder
+            .find_tag_unsigned::<u16>(Tag::Compression)
+            .ok()
+            .flatten()
+            .map(CompressionMethod::from_u16_exhaustive)
+            .unwrap_or(CompressionMethod::None);
+        decoder.get_chunk_type() == ChunkType::Tile && COMPRESSIONS.contains(&compression)
+    }
+
+    pub fn open(path: &PathBuf) -> Result<TiffSlide> {
+        let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+        let mut directories = Vec::new();
+        let mut associated = Vec::new();
+        let mut properties = HashMap::new();
+        let mut resolution = None;
+        let mut index = 0;
+        while let Some(ifd) = decoder.ifd_pointer() {
+            let (width, height) = decoder.dimensions()?;
+            let tiled = decoder.get_chunk_type() == ChunkType::Tile;
+            let description = decoder
+                .get_tag_ascii_string(Tag::ImageDescription)
+                .unwrap_or_default();
+            if index == 0 {
+                for (tag, name) in [
+                    (Tag::ImageDescription, "ImageDescription"),
+                    (Tag::Software, "Software"),
+                    (Tag::Make, "Make"),
+                    (Tag::Model, "Model"),
+                    (Tag::DateTime, "DateTime"),
+                ] {
+                    if let Ok(value) = decoder.get_tag_ascii_string(tag) {
+                        properties.insert(format!("tiff.{}", name), value);
+                    }
+                }
+                let unit = decoder.find_tag_unsigned(Tag::ResolutionUnit)?;
+                let x = ppm(decoder.find_tag(Tag::XResolution)?, unit);
+                let y = ppm(decoder.find_tag(Tag::YResolution)?, unit);
+                resolution = x.zip(y);
+                let mpp = description_mpp(&description, "MPP")
+                    .or_else(|| description_mpp(&description, "PhysicalSizeX"));
+                if let Some(mpp) = mpp {
+                    let ppm = (1e6 / mpp).round() as u64;
+                    resolution = Some((ppm, ppm));
+                }
+                let sub_ifds = decoder.find_tag_unsigned_vec::<u64>(Tag::SubIfd)?;
+                for sub_ifd in sub_ifds.unwrap_or_default() {
+                    let ifd = IfdPointer(sub_ifd);
+                    let sub = decoder.read_directory(ifd)?;
+                    let mut tags = decoder.read_directory_tags(&sub);
+                    directories.push(Directory {
+                        ifd,
+                        width: tags.get_tag_u64(Tag::ImageWidth)?,
+                        height: tags.get_tag_u64(Tag::ImageLength)?,
+                        tiled: tags.find_tag(Tag::TileWidth)?.is_some(),
+                    });
+                }
+            }
+            let directory = Directory {
+                ifd,
+                width: width as u64,
+                height: height as u64,
+                tiled,
+            };
+            // Aperio stores the label and the macro image as stripped images.
+            let lower = description.to_lowercase();
+            match ["label", "macro", "thumbnail"]
+                .iter()
+                .find(|n| lower.contains(*n))
+            {
+                Some(name) if !tiled => associated.push((name.to_string(), directory)),
+                _ => directories.push(directory),
+            }
+            if !decoder.more_images() {
+                break;
+            }
+            decoder.next_image()?;
+            index += 1;
+        }
+
+        let Some(base) = directories
+            .iter()
+            .filter(|d| d.tiled)
+            .max_by_key(|d| d.width * d.height)
+            .copied()
+        else {
+            bail!("{} has no tiled image", path.display());
+        };
+        let mut pyramid: Vec<Directory> = directories
+            .into_iter()
+            .filter(|d| d.tiled && d.width > 0 && d.height > 0)
+            .filter(|d| {
+                let dx = base.width as f64 / d.width as f64;
+                let dy = base.height as f64 / d.height as f64;
+                (dx / dy - 1.0).abs() < 0.02
+            })
+            .collect();
+        pyramid.sort_by_key(|d| std::cmp::Reverse(d.width));
+        pyramid.dedup_by_key(|d| d.width);
+        let levels = pyramid
+            .into_iter()
+            .map(|directory| Level {
+                downsample: base.width as f64 / directory.width as f64,
+                directory,
+                decoders: Mutex::new(Vec::new()),
+            })
+            .collect::<Vec<_>>();
+        log::debug!(
+            "Found {} levels and {} associated images in {}",
+            levels.len(),
+            associated.len(),
+            path.display()
+        );
+        let jpeg = JpegTiles::open(path, &base)?;
+
+        Ok(TiffSlide {
+            path: path.clone(),
+            levels,
+            associated,
+            properties,
+            resolution,
+            jpeg,
+        })
+    }
+
+    fn level(&self, level: i32) -> Result<&Level> {
+        match self.levels.get(level as usize) {
+            Some(l) if level >= 0 => Ok(l),
+            _ => bail!("Level {} does not exist", level),
+        }
+    }
+
+    /// Runs `f` with an idle decoder of the level.
+    fn with_decoder<T>(
+        &self,
+        level: &Level,
+        f: impl FnOnce(&mut TiffDecoder) -> Result<T>,
+    ) -> Result<T> {
+        let idle = level.decoders.lock().unwrap().pop();
+        let mut decoder = match idle {
+            Some(d) => d,
+            None => open_decoder(&self.path, level.directory.ifd)?,
+        };
+        let result = f(&mut decoder);
+        level.decoders.lock().unwrap().push(decoder);
+        result
+    }
+
+    fn read_tile(decoder: &mut TiffDecoder, index: u32) -> Result<RgbImage> {
+        let (w, h) = decoder.chunk_data_dimensions(index);
+        let color = decoder.colortype()?;
+        let DecodingResult::U8(samples) = decoder.read_chunk(index)? else {
+            bail!("Only 8 bit images are supported");
+        };
+        to_rgb(color, samples, w, h)
+    }
+
+    /// Reads a region in pixels of the level from all tiles it overlaps.
+    fn read_level_region(
+        &self,
+        level: &Level,
+        x: i64,
+        y: i64,
+        width: u32,
+        height: u32,
+    ) -> Result<RgbImage> {
+        let white = Rgb([255, 255, 255]);
+        let mut region: RgbImage = ImageBuffer::from_pixel(width, height, white);
+        let (w, h) = (level.directory.width as i64, level.directory.height as i64);
+        let x0 = x.clamp(0, w);
+        let y0 = y.clamp(0, h);
+        let x1 = (x + width as i64).clamp(0, w);
+        let y1 = (y + height as i64).clamp(0, h);
+        if x1 <= x0 || y1 <= y0 {
+            return Ok(region);
+        }
+        self.with_decoder(level, |decoder| {
+            let (tw, th) = decoder.chunk_dimensions();
+            let (tw, th) = (tw as i64, th as i64);
+            let columns = (w + tw - 1) / tw;
+            for row in y0 / th..=(y1 - 1) / th {
+                for column in x0 / tw..=(x1 - 1) / tw {
+                    let index = (row * columns + column) as u32;
+                    let tile = TiffSlide::read_tile(decoder, index)?;
+                    imageops::replace(&mut region, &tile, column * tw - x, row * th - y);
+                }
+            }
+            Ok(())
+        })?;
+        Ok(region)
+    }
+}
+
+impl SlideSource for TiffSlide {
+    fn vendor(&self) -> String {
+        let description = self
+            .properties
+            .get("tiff.ImageDescription")
+            .map(|d| d.as_str())
+            .unwrap_or("");
+        if description.starts_with("Aperio") {
+            "aperio".to_owned()
+        } else if description.contains("<OME") {
+            "ome-tiff".to_owned()
+        } else {
+            "generic-tiff".to_owned()
+        }
+    }
+
+    fn size(&self) -> (u64, u64) {
+        let base = &self.levels[0].directory;
+        (base.width, base.height)
+    }
+
+    fn resolution(&self) -> Result<(u64, u64)> {
+        match self.resolution {
+            Some(r) => Ok(r),
+            None => bail!("Could not read resolution from {}", self.path.display()),
+        }
+    }
+
+    fn read_region(&self, x: i64, y: i64, width: i64, height: i64) -> Result<RgbImage> {
+        self.read_region_level(x, y, 0, width, height)
+    }
+
+    fn properties(&self) -> Result<HashMap<String, String>> {
+        Ok(self.properties.clone())
+    }
+
+    fn level_count(&self) -> Result<i32> {
+        Ok(self.levels.len() as i32)
+    }
+
+    fn level_dimensions(&self, level: i32) -> Result<(u64, u64)> {
+        let directory = &self.level(level)?.directory;
+        Ok((directory.width, directory.height))
+    }
+
+    fn level_downsample(&self, level: i32) -> Result<f64> {
+        Ok(self.level(level)?.downsample)
+    }
+
+    fn read_region_level(
+        &self,
+        x: i64,
+        y: i64,
+        level: i32,
+        width: i64,
+        height: i64,
+    ) -> Result<RgbImage> {
+        if width < 0 || height < 0 {
+            bail!("Invalid region size {}x{}", width, height);
+        }
+        let level = self.level(level)?;
+        let lx = (x as f64 / level.downsample).floor() as i64;
+        let ly = (y as f64 / level.downsample).floor() as i64;
+        self.read_level_region(level, lx, ly, width as u32, height as u32)
+    }
+
+    fn read_raw_tile(&self, x: i64, y: i64, size: u64) -> Result<Option<Vec<u8>>> {
+        let Some(jpeg) = &self.jpeg else {
+            return Ok(None);
+        };
+        if x < 0 || y < 0 {
+            return Ok(None);
+        }
+        let (width, height) = self.size();
+        let (x, y) = (x as u64, y as u64);
+        // Edge tiles are padded by the scanner, they are read as regions instead.
+        if jpeg.tile_width != size
+            || jpeg.tile_height != size
+            || x % size != 0
+            || y % size != 0
+            || x + size > width
+            || y + size > height
+        {
+            return Ok(None);
+        }
+        jpeg.read(x / size, y / size)
+    }
+
+    fn associated_image_names(&self) -> Result<Vec<String>> {
+        Ok(self.associated.iter().map(|(n, _)| n.clone()).collect())
+    }
+
+    fn read_associated_image(&self, name: &str) -> Result<RgbImage> {
+        let Some((_, directory)) = self.associated.iter().find(|(n, _)| n == name) else {
+            bail!("No associated image {}", name);
+        };
+        let mut decoder = open_decoder(&self.path, directory.ifd)?;
+        let color = decoder.colortype()?;
+        let DecodingResult::U8(samples) = decoder.read_image()? else {
+            bail!("Only 8 bit images are supported");
+        };
+        to_rgb(
+            color,
+            samples,
+            directory.width as u32,
+            directory.height as u32,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{complete_jpeg, description_mpp, TiffSlide, ADOBE_RGB};
+    use crate::convert::SlideSource;
+    use crate::export::export_tiff;
+    use crate::{Database, SlideData, Tile, TileCodec};
+    use anyhow::Result;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn read_exported_pyramid() -> Result<()> {
+        let dir = std::env::temp_dir();
+        let db_path = dir.join(format!("pamly_tiff_slide_{}.sqlite", std::process::id()));
+        if db_path.is_file() {
+            std::fs::remove_file(&db_path)?;
+        }
+        let db = Database::create(
+            &db_path,
+            SlideData::new(16, 2, 32, 32, 4_000_000, 4_000_000),
+        )?;
+        let red = Rgb([200, 30, 30]);
+        for (x, y, level) in [(0, 0, 1), (1, 1, 1), (0, 0, 0)] {
+            let mut tile = Tile::new((x, y), level, 16);
+            tile.set_image(RgbImage::from_pixel(16, 16, red))?;
+            db.write(&tile)?;
+        }
+        drop(db);
+        let tiff_path = dir.join(format!("pamly_tiff_slide_{}.ome.tiff", std::process::id()));
+        export_tiff(&db_path, &tiff_path, false)?;
+
+        assert!(TiffSlide::detect(&tiff_path));
+        assert!(!TiffSlide::detect(&db_path));
+        let slide = TiffSlide::open(&tiff_path)?;
+        assert_eq!(slide.vendor(), "ome-tiff");
+        assert_eq!(slide.size(), (32, 32));
+        assert_eq!(slide.resolution()?, (4_000_000, 4_000_000));
+        assert_eq!(slide.level_count()?, 2);
+        assert_eq!(slide.level_dimensions(1)?, (16, 16));
+        assert_eq!(slide.level_downsample(1)?, 2.0);
+
+        let close = |p: &Rgb<u8>, q: Rgb<u8>| p.0.iter().zip(q.0).all(|(a, b)| a.abs_diff(b) < 8);
+        let region = slide.read_region(8, 8, 16, 16)?;
+        assert!(close(region.get_pixel(0, 0), red));
+        assert!(close(region.get_pixel(15, 0), Rgb([255, 255, 255])));
+        assert!(close(region.get_pixel(15, 15), red));
+        let region = slide.read_region(24, 24, 16, 16)?;
+        assert!(close(region.get_pixel(0, 0), red));
+        assert_eq!(region.get_pixel(15, 15), &Rgb([255, 255, 255]));
+        let small = slide.read_region_level(0, 0, 1, 16, 16)?;
+        assert!(close(small.get_pixel(8, 8), red));
+        assert!(slide.read_region_level(0, 0, 2, 4, 4).is_err());
+
+        let stored = Database::open(&db_path)?.read_data((1, 1), 1)?;
+        assert_eq!(slide.read_raw_tile(16, 16, 16)?, stored);
+        assert!(slide.read_raw_tile(8, 16, 16)?.is_none());
+        assert!(slide.read_raw_tile(0, 0, 32)?.is_none());
+
+        assert_eq!(
+            description_mpp("Aperio |MPP = 0.2499|AppMag = 40", "MPP"),
+            Some(0.2499)
+        );
+        std::fs::remove_file(tiff_path)?;
+        std::fs::remove_file(db_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn abbreviated_jpeg() -> Result<()> {
+        let codec = TileCodec::default();
+        let image = RgbImage::from_pixel(16, 16, Rgb([200, 30, 30]));
+        let full = codec.encode(&image)?;
+        // Split the stream before the frame header into tables and an abbreviated tile.
+        let frame = full.windows(2).position(|m| m == [0xFF, 0xC0]).unwrap();
+        let tables = [&full[..frame], &[0xFF, 0xD9]].concat();
+        let tile = [&[0xFF, 0xD8], &full[frame..]].concat();
+
+        assert_eq!(complete_jpeg(&tile, Some(&tables), false), full);
+        let rgb = complete_jpeg(&tile, Some(&tables), true);
+        assert_eq!(&rgb[2..18], &ADOBE_RGB);
+        assert_eq!(codec.decode(&rgb)?.dimensions(), (16, 16));
+        Ok(())
+    }
+}
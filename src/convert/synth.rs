@@ -0,0 +1,174 @@
+use image::{ImageBuffer, Rgb, RgbImage};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::ImageSlide;
+
+/// Resolution of synthetic slides in pixels per meter (0.25 microns per pixel)
+pub const SYNTH_PPM: u64 = 4_000_000;
+
+/// Region of a synthetic slide covered with tissue, in pixels of the full resolution level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Rect {
+        x: u64,
+        y: u64,
+        width: u64,
+        height: u64,
+    },
+    Ellipse {
+        cx: f64,
+        cy: f64,
+        rx: f64,
+        ry: f64,
+    },
+}
+
+impl Shape {
+    pub fn contains(&self, x: u64, y: u64) -> bool {
+        match *self {
+            Shape::Rect {
+                x: rx,
+                y: ry,
+                width,
+                height,
+            } => x >= rx && x < rx + width && y >= ry && y < ry + height,
+            Shape::Ellipse { cx, cy, rx, ry } => {
+                let dx = (x as f64 + 0.5 - cx) / rx;
+                let dy = (y as f64 + 0.5 - cy) / ry;
+                dx * dx + dy * dy <= 1.0
+            }
+        }
+    }
+}
+
+/// A generated slide with tissue-like texture inside known shapes and blank background elsewhere.
+/// The same seed always renders the same image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SynthSlide {
+    pub width: u64,
+    pub height: u64,
+    pub seed: u64,
+    /// Edge length of the texture cells in pixels
+    pub cell_size: u64,
+    pub shapes: Vec<Shape>,
+}
+
+const BACKGROUND: [u8; 3] = [246, 244, 247];
+const STROMA: [u8; 3] = [222, 140, 190];
+const NUCLEUS: [u8; 3] = [92, 48, 128];
+
+impl SynthSlide {
+    /// An empty slide, add tissue with `with_shape`.
+    pub fn new(width: u64, height: u64, seed: u64) -> SynthSlide {
+        SynthSlide {
+            width,
+            height,
+            seed,
+            cell_size: 4,
+            shapes: Vec::new(),
+        }
+    }
+
+    /// A slide with a few large tissue blobs and some small islands at random positions.
+    pub fn random(width: u64, height: u64, seed: u64) -> SynthSlide {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut slide = SynthSlide::new(width, height, seed);
+        let (w, h) = (width as f64, height as f64);
+        let side = w.min(h);
+
+        for _ in 0..rng.gen_range(1..=3) {
+            slide.shapes.push(Shape::Ellipse {
+                cx: rng.gen_range(0.25..0.75) * w,
+                cy: rng.gen_range(0.25..0.75) * h,
+                rx: rng.gen_range(0.1..0.25) * side,
+                ry: rng.gen_range(0.1..0.25) * side,
+            });
+        }
+        for _ in 0..rng.gen_range(2..=6) {
+            let r = rng.gen_range(0.01..0.02) * side;
+            slide.shapes.push(Shape::Ellipse {
+                cx: rng.gen_range(0.0..w),
+                cy: rng.gen_range(0.0..h),
+                rx: r,
+                ry: r,
+            });
+        }
+        slide
+    }
+
+    pub fn with_shape(mut self, shape: Shape) -> SynthSlide {
+        self.shapes.push(shape);
+        self
+    }
+
+    pub fn with_cell_size(mut self, cell_size: u64) -> SynthSlide {
+        self.cell_size = std::cmp::max(cell_size, 1);
+        self
+    }
+
+    /// Checks whether the pixel is covered with tissue.
+    pub fn is_tissue(&self, x: u64, y: u64) -> bool {
+        self.shapes.iter().any(|s| s.contains(x, y))
+    }
+
+    pub fn render(&self) -> RgbImage {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let cells_x = self.width.div_ceil(self.cell_size);
+        let cells_y = self.height.div_ceil(self.cell_size);
+
+        // Each texture cell is either a nucleus or stroma, both with some jitter.
+        let cells: Vec<[u8; 3]> = (0..cells_x * cells_y)
+            .map(|_| {
+                let base = if rng.gen_bool(0.3) { NUCLEUS } else { STROMA };
+                let jitter: i16 = rng.gen_range(-20..=20);
+                base.map(|c| (c as i16 + jitter).clamp(0, 255) as u8)
+            })
+            .collect();
+
+        ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let (x, y) = (x as u64, y as u64);
+            if !self.is_tissue(x, y) {
+                return Rgb(BACKGROUND);
+            }
+            let cell = (y / self.cell_size) * cells_x + x / self.cell_size;
+            Rgb(cells[cell as usize])
+        })
+    }
+
+    /// Renders the slide into a source the converter can read.
+    pub fn to_source(&self) -> ImageSlide {
+        ImageSlide::new(self.render()).with_resolution(SYNTH_PPM, SYNTH_PPM)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Shape, SynthSlide, BACKGROUND};
+    use image::Rgb;
+
+    #[test]
+    fn render_geometry() {
+        let slide = SynthSlide::new(32, 16, 7).with_shape(Shape::Rect {
+            x: 8,
+            y: 4,
+            width: 8,
+            height: 8,
+        });
+        let image = slide.render();
+        assert_eq!(image.dimensions(), (32, 16));
+        assert_eq!(image.get_pixel(0, 0), &Rgb(BACKGROUND));
+        assert_eq!(image.get_pixel(16, 4), &Rgb(BACKGROUND));
+        assert_ne!(image.get_pixel(8, 4), &Rgb(BACKGROUND));
+        assert_ne!(image.get_pixel(15, 11), &Rgb(BACKGROUND));
+        assert_eq!(image, slide.render());
+    }
+
+    #[test]
+    fn random_is_seeded() {
+        let a = SynthSlide::random(1000, 800, 3);
+        assert_eq!(a, SynthSlide::random(1000, 800, 3));
+        assert_ne!(a, SynthSlide::random(1000, 800, 4));
+        assert!(a.shapes.len() >= 3);
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::Result;
+use image::{imageops, GrayImage, Pixel, RgbImage};
+use imageops::FilterType;
+use imageproc::edges::canny;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+
+use super::{Config, SlideSource};
+use crate::types::Stain;
+
+/// Longer side of the overview the saturation threshold is computed on
+pub const OVERVIEW_SIZE: u64 = 1024;
+
+/// Pixels darker than this have no reliable hue, they count as unsaturated.
+const MIN_VALUE: u8 = 20;
+
+/// Decides whether a tile contains enough tissue to be stored.
+pub trait TissueDetector: Send + Sync {
+    fn is_tissue(&self, image: &RgbImage) -> bool;
+}
+
+/// The tissue detectors that can be selected in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, Serialize, Deserialize)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectorKind {
+    /// Dark pixels and Canny edges, works well for H&E
+    Edge,
+    /// Otsu's threshold on the HSV saturation of an overview of the slide
+    Otsu,
+    /// Tissue if either the edge or the saturation detector finds it
+    Combined,
+}
+
+impl DetectorKind {
+    /// Pale IHC stains have too few dark pixels and edges, they also use the saturation.
+    pub fn for_stain(stain: Stain) -> DetectorKind {
+        match stain {
+            Stain::Unknown | Stain::HE => DetectorKind::Edge,
+            Stain::CD3 | Stain::CD20 | Stain::CD30 | Stain::CD68 => DetectorKind::Combined,
+        }
+    }
+}
+
+/// Requires a share of dark pixels and of edges in a downscaled tile.
+pub struct EdgeDetector {
+    dark_threshold: u8,
+    min_dark_content: f64,
+    edge_detect_size: u32,
+    edge_low_threshold: f32,
+    edge_high_threshold: f32,
+    min_edge_content: f64,
+}
+
+impl EdgeDetector {
+    pub fn new(config: &Config) -> EdgeDetector {
+        EdgeDetector {
+            dark_threshold: (255.0 * config.dark_threshold) as u8,
+            min_dark_content: config.min_dark_content,
+            edge_detect_size: config.edge_detect_size as u32,
+            edge_low_threshold: config.edge_low_threshold,
+            edge_high_threshold: config.edge_high_threshold,
+            min_edge_content: config.min_edge_content,
+        }
+    }
+}
+
+impl TissueDetector for EdgeDetector {
+    fn is_tissue(&self, image: &RgbImage) -> bool {
+        let (w, h) = image.dimensions();
+        let mut gray = GrayImage::new(w, h);
+
+        let mut dark_pixels = 0;
+        for (g_p, rgb_p) in gray.pixels_mut().zip(image.pixels()) {
+            *g_p = rgb_p.to_luma();
+            if g_p[0] < self.dark_threshold {
+                dark_pixels += 1;
+            }
+        }
+        let dark_content = dark_pixels as f64 / (w as f64 * h as f64);
+        if dark_content < self.min_dark_content {
+            return false;
+        }
+
+        let size = self.edge_detect_size;
+        let resized = imageops::resize(&gray, size, size, FilterType::Triangle);
+        let edges = canny(&resized, self.edge_low_threshold, self.edge_high_threshold);
+        let edge_count = edges.into_raw().iter().filter(|p| **p != 0).count();
+        let edge_percent = edge_count as f64 / (size * size) as f64;
+        edge_percent > self.min_edge_content
+    }
+}
+
+/// HSV saturation of a pixel, scaled to 0..255.
+fn saturation(pixel: &image::Rgb<u8>) -> u8 {
+    let max = *pixel.0.iter().max().unwrap();
+    let min = *pixel.0.iter().min().unwrap();
+    if max < MIN_VALUE {
+        return 0;
+    }
+    ((max - min) as u32 * 255 / max as u32) as u8
+}
+
+/// Otsu's threshold, maximizes the variance between the two classes of the histogram.
+fn otsu_threshold(histogram: &[u64; 256]) -> u8 {
+    let total: u64 = histogram.iter().sum();
+    let sum: f64 = histogram
+        .iter()
+        .enumerate()
+        .map(|(i, n)| i as f64 * *n as f64)
+        .sum();
+    let mut background = 0;
+    let mut background_sum = 0.0;
+    let mut best = (0.0, 0);
+    for (i, n) in histogram.iter().enumerate() {
+        background += n;
+        background_sum += i as f64 * *n as f64;
+        let foreground = total - background;
+        if background == 0 || foreground == 0 {
+            continue;
+        }
+        let mean_b = background_sum / background as f64;
+        let mean_f = (sum - background_sum) / foreground as f64;
+        let variance = background as f64 * foreground as f64 * (mean_b - mean_f).powi(2);
+        if variance > best.0 {
+            best = (variance, i as u8);
+        }
+    }
+    best.1
+}
+
+/// Counts pixels more saturated than a threshold found once on an overview of the slide.
+pub struct OtsuDetector {
+    threshold: u8,
+    min_tissue_content: f64,
+}
+
+impl OtsuDetector {
+    /// Computes the threshold on the overview, it is at least `min_saturation` (0 to 1).
+    pub fn from_overview(
+        overview: &RgbImage,
+        min_saturation: f64,
+        min_tissue_content: f64,
+    ) -> OtsuDetector {
+        let mut histogram = [0; 256];
+        for pixel in overview.pixels() {
+            histogram[saturation(pixel) as usize] += 1;
+        }
+        let floor = (255.0 * min_saturation).round() as u8;
+        let threshold = std::cmp::max(otsu_threshold(&histogram), floor);
+        log::debug!("Saturation threshold {}", threshold);
+        OtsuDetector {
+            threshold,
+            min_tissue_content,
+        }
+    }
+}
+
+impl TissueDetector for OtsuDetector {
+    fn is_tissue(&self, image: &RgbImage) -> bool {
+        let (w, h) = image.dimensions();
+        let saturated = image
+            .pixels()
+            .filter(|p| saturation(p) > self.threshold)
+            .count();
+        saturated as f64 / (w as f64 * h as f64) >= self.min_tissue_content
+    }
+}
+
+/// Tissue if either detector finds it, pale tiles pass through the saturation.
+pub struct CombinedDetector {
+    edge: EdgeDetector,
+    otsu: OtsuDetector,
+}
+
+impl TissueDetector for CombinedDetector {
+    fn is_tissue(&self, image: &RgbImage) -> bool {
+        self.edge.is_tissue(image) || self.otsu.is_tissue(image)
+    }
+}
+
+/// Creates the detector selected in the config, the saturation threshold is computed
+/// on an overview of the slide.
+pub fn tissue_detector(
+    slide: &dyn SlideSource,
+    config: &Config,
+) -> Result<Box<dyn TissueDetector>> {
+    let kind = config.detector_kind();
+    log::debug!("Detecting tissue with the {} detector", kind);
+    let otsu = || -> Result<OtsuDetector> {
+        let overview = slide.thumbnail(OVERVIEW_SIZE)?;
+        Ok(OtsuDetector::from_overview(
+            &overview,
+            config.min_saturation,
+            config.min_tissue_content,
+        ))
+    };
+    let detector: Box<dyn TissueDetector> = match kind {
+        DetectorKind::Edge => Box::new(EdgeDetector::new(config)),
+        DetectorKind::Otsu => Box::new(otsu()?),
+        DetectorKind::Combined => Box::new(CombinedDetector {
+            edge: EdgeDetector::new(config),
+            otsu: otsu()?,
+        }),
+    };
+    Ok(detector)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{otsu_threshold, DetectorKind, OtsuDetector, TissueDetector};
+    use crate::types::Stain;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn otsu_on_pale_stain() {
+        let mut histogram = [0; 256];
+        histogram[10] = 100;
+        histogram[60] = 50;
+        let threshold = otsu_threshold(&histogram);
+        assert!((10..60).contains(&threshold));
+
+        // A pale brown stain on white background, with about a third of tissue
+        let background = Rgb([240, 238, 240]);
+        let stain = Rgb([215, 185, 160]);
+        let mut overview = RgbImage::from_pixel(30, 30, background);
+        for (x, _, p) in overview.enumerate_pixels_mut() {
+            if x < 10 {
+                *p = stain;
+            }
+        }
+        let detector = OtsuDetector::from_overview(&overview, 0.05, 0.2);
+        assert!(detector.is_tissue(&RgbImage::from_pixel(8, 8, stain)));
+        assert!(!detector.is_tissue(&RgbImage::from_pixel(8, 8, background)));
+        assert!(!detector.is_tissue(&RgbImage::from_pixel(8, 8, Rgb([0, 0, 0]))));
+
+        assert_eq!(DetectorKind::for_stain(Stain::HE), DetectorKind::Edge);
+        assert_eq!(DetectorKind::for_stain(Stain::CD20), DetectorKind::Combined);
+        assert_eq!("OTSU".parse::<DetectorKind>().unwrap(), DetectorKind::Otsu);
+    }
+}
@@ -0,0 +1,88 @@
+use crate::Database;
+use anyhow::{bail, Result};
+use image::{DynamicImage, ImageFormat, RgbImage};
+use sqlite::State;
+use std::io::Cursor;
+
+const TABLE: &str = "associated_images";
+
+impl Database {
+    /// Stores an associated image of the slide, e.g. the label or macro image, as png.
+    pub fn write_associated_image(&self, name: &str, image: &RgbImage) -> Result<()> {
+        self.check_writeable()?;
+        let mut bytes = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image.clone()).write_to(&mut bytes, ImageFormat::Png)?;
+        let data = bytes.into_inner();
+
+        let statement = "INSERT INTO associated_images (name, width, height, image)
+                     VALUES (?, ?, ?, ?)
+                     ON CONFLICT(name)
+                     DO UPDATE SET
+                        width=excluded.width,
+                        height=excluded.height,
+                        image=excluded.image;
+        ";
+        let mut statement = self.db.prepare(statement)?;
+        statement.bind((1, name))?;
+        statement.bind((2, image.width() as i64))?;
+        statement.bind((3, image.height() as i64))?;
+        statement.bind((4, &data[..]))?;
+        match statement.next()? {
+            State::Done => Ok(()),
+            _ => bail!("Failed insert"),
+        }
+    }
+
+    pub fn list_associated_images(&self) -> Result<Vec<String>> {
+        if !self.table_exists(TABLE.to_owned())? {
+            return Ok(Vec::new());
+        }
+        let statement = "SELECT name from associated_images ORDER BY name";
+        let mut statement = self.db.prepare(statement)?;
+        let mut names = Vec::new();
+        while statement.next()? == State::Row {
+            names.push(statement.read::<String, _>(0)?);
+        }
+        Ok(names)
+    }
+
+    pub fn read_associated_image(&self, name: &str) -> Result<Option<RgbImage>> {
+        if !self.table_exists(TABLE.to_owned())? {
+            return Ok(None);
+        }
+        let statement = "SELECT image from associated_images WHERE name = ?";
+        let mut statement = self.db.prepare(statement)?;
+        statement.bind((1, name))?;
+        match statement.next()? {
+            State::Row => {
+                let data = statement.read::<Vec<u8>, _>(0)?;
+                let image = image::load_from_memory(&data)?.to_rgb8();
+                Ok(Some(image))
+            }
+            State::Done => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Result;
+    use crate::database::testing::test_db;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn associated_images() -> Result<()> {
+        let (db, path) = test_db("associated")?;
+        let label = RgbImage::from_pixel(3, 2, Rgb([1, 2, 3]));
+        db.write_associated_image("label", &label)?;
+        db.write_associated_image("macro", &label)?;
+        db.write_associated_image("label", &RgbImage::new(5, 5))?;
+
+        assert_eq!(db.list_associated_images()?, vec!["label", "macro"]);
+        assert_eq!(db.read_associated_image("macro")?, Some(label));
+        assert_eq!(db.read_associated_image("label")?.unwrap().width(), 5);
+        assert_eq!(db.read_associated_image("thumbnail")?, None);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
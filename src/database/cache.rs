@@ -0,0 +1,181 @@
+use image::RgbImage;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::Database;
+
+/// Position and level of a tile, (x, y, level)
+pub type Key = (u64, u64, u64);
+
+/// Least recently used cache of decoded tiles.
+/// Tiles known to be missing are cached as `None`, so background is not queried again.
+pub struct TileCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<Key, (u64, Option<RgbImage>)>,
+    order: BTreeMap<u64, Key>,
+    hits: u64,
+    misses: u64,
+}
+
+impl TileCache {
+    pub fn new(capacity: usize) -> TileCache {
+        TileCache {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.entries.contains_key(&key)
+    }
+
+    pub fn get(&mut self, key: Key) -> Option<&Option<RgbImage>> {
+        let Some((tick, _)) = self.entries.get(&key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        self.order.remove(tick);
+        self.tick += 1;
+        self.order.insert(self.tick, key);
+        let entry = self.entries.get_mut(&key)?;
+        entry.0 = self.tick;
+        Some(&entry.1)
+    }
+
+    pub fn insert(&mut self, key: Key, image: Option<RgbImage>) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.remove(key);
+        self.tick += 1;
+        self.order.insert(self.tick, key);
+        self.entries.insert(key, (self.tick, image));
+        self.evict();
+    }
+
+    pub fn remove(&mut self, key: Key) {
+        if let Some((tick, _)) = self.entries.remove(&key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&key);
+        }
+    }
+}
+
+impl Database {
+    /// Keeps up to `tiles` decoded tiles in memory, 0 disables the cache.
+    pub fn set_cache_size(&self, tiles: usize) {
+        self.cache.borrow_mut().set_capacity(tiles);
+    }
+
+    pub fn clear_cache(&self) {
+        self.cache.borrow_mut().clear();
+    }
+
+    /// Number of cache hits and misses since the database was opened.
+    pub fn cache_stats(&self) -> (u64, u64) {
+        let cache = self.cache.borrow();
+        (cache.hits, cache.misses)
+    }
+
+    pub(crate) fn invalidate(&self, pos: (u64, u64), level: u64) {
+        self.cache.borrow_mut().remove((pos.0, pos.1, level));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TileCache;
+    use crate::database::testing::test_db;
+    use crate::Tile;
+    use anyhow::Result;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = TileCache::new(2);
+        cache.insert((0, 0, 0), Some(RgbImage::new(1, 1)));
+        cache.insert((1, 0, 0), None);
+        assert!(cache.get((0, 0, 0)).is_some());
+        cache.insert((2, 0, 0), None);
+        assert!(cache.get((1, 0, 0)).is_none());
+        assert!(cache.get((0, 0, 0)).is_some());
+        assert_eq!(cache.get((2, 0, 0)), Some(&None));
+        assert_eq!((cache.hits, cache.misses), (3, 1));
+
+        cache.set_capacity(0);
+        assert!(cache.get((0, 0, 0)).is_none());
+        cache.insert((0, 0, 0), None);
+        assert!(cache.get((0, 0, 0)).is_none());
+    }
+
+    #[test]
+    fn cached_reads() -> Result<()> {
+        let (db, path) = test_db("cache")?;
+        db.read_region((0, 0), (8, 8))?;
+        assert_eq!(db.cache_stats(), (0, 0));
+
+        db.set_cache_size(16);
+        let first = db.read_region((1, 1), (6, 6))?;
+        let (hits, misses) = db.cache_stats();
+        assert_eq!(hits, 0);
+        assert!(misses > 0);
+        let second = db.read_region((1, 1), (6, 6))?;
+        assert_eq!(first.image()?, second.image()?);
+        assert_eq!(db.cache_stats(), (4, misses));
+
+        // Missing tiles are cached as well, writes replace the cached tile.
+        assert!(db.read((0, 1), 1)?.is_empty());
+        let mut tile = Tile::new((0, 1), 1, 4);
+        tile.set_image(RgbImage::from_pixel(4, 4, Rgb([255, 0, 0])))?;
+        db.write(&tile)?;
+        assert!(!db.read((0, 1), 1)?.is_empty());
+        db.delete((0, 0), 1)?;
+        assert!(db.read((0, 0), 1)?.is_empty());
+        let patch = db.read_region((0, 0), (8, 8))?;
+        assert_eq!(patch.image()?.get_pixel(0, 0), &Rgb([255, 255, 255]));
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn region_larger_than_cache() -> Result<()> {
+        let (db, path) = test_db("cache_small")?;
+        db.set_cache_size(1);
+        assert_eq!(db.read_many((0, 0), (2, 2), 1)?.len(), 3);
+        assert!(!db.read((1, 1), 1)?.is_empty());
+        assert!(!db.read((0, 0), 1)?.is_empty());
+        assert!(db.read((0, 1), 1)?.is_empty());
+        assert_eq!(db.read_many((0, 0), (2, 2), 1)?.len(), 3);
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -1,14 +1,18 @@
 use anyhow::{bail, Result};
+use pyo3::pyclass;
 use sqlite::{Connection, OpenFlags};
-use std::path::PathBuf;
+use std::{cell::RefCell, path::PathBuf};
 
-use super::SlideData;
+use super::{SlideData, TileCache};
+use crate::TileCodec;
 
+#[pyclass]
 pub struct Database {
-    pub db: Connection,
+    pub(super) db: Connection,
     path: PathBuf,
     pub data: SlideData,
     writeable: bool,
+    pub(crate) cache: RefCell<TileCache>,
 }
 
 impl Database {
@@ -20,6 +24,7 @@ impl Database {
             path: path.clone(),
             writeable: true,
             data: data.clone(),
+            cache: RefCell::new(TileCache::new(0)),
         };
         database.check_tables()?;
         data.write_to(&database.db)?;
@@ -39,6 +44,7 @@ impl Database {
             path: path.clone(),
             writeable: true,
             data,
+            cache: RefCell::new(TileCache::new(0)),
         })
     }
     pub fn open_readonly(path: &PathBuf) -> Result<Database> {
@@ -50,6 +56,7 @@ impl Database {
             path: path.clone(),
             writeable: false,
             data,
+            cache: RefCell::new(TileCache::new(0)),
         })
     }
     pub fn is_writeable(&self) -> bool {
@@ -64,6 +71,9 @@ impl Database {
     pub fn tile_size(&self) -> u64 {
         self.data.tile_size
     }
+    pub fn codec(&self) -> TileCodec {
+        self.data.codec
+    }
     pub fn levels(&self) -> u64 {
         self.data.levels
     }
@@ -0,0 +1,143 @@
+use crate::{Database, Embeddings, Metric};
+use anyhow::{bail, Result};
+use sqlite::State;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone)]
+pub struct Neighbour {
+    pub tile: u64,
+    pub pos: (u64, u64),
+    pub level: u64,
+    pub distance: f64,
+}
+
+impl Database {
+    fn tile_ids(&self) -> Result<HashSet<u64>> {
+        let mut statement = self.db.prepare("SELECT id from tiles")?;
+        let mut ids = HashSet::new();
+        while statement.next()? == State::Row {
+            ids.insert(statement.read::<i64, _>(0)? as u64);
+        }
+        Ok(ids)
+    }
+
+    /// Computes the k nearest neighbours of every embedded tile and stores them in the distances table.
+    /// Returns the number of stored distances.
+    pub fn write_neighbours(
+        &self,
+        embeddings: &Embeddings,
+        k: usize,
+        metric: Metric,
+    ) -> Result<u64> {
+        self.check_writeable()?;
+        let tile_ids = self.tile_ids()?;
+        let mut known = Embeddings {
+            ids: Vec::new(),
+            vectors: Vec::new(),
+        };
+        for (id, vector) in embeddings.ids.iter().zip(&embeddings.vectors) {
+            if !tile_ids.contains(id) {
+                log::warn!("Ignoring embedding of unknown tile {}", id);
+                continue;
+            }
+            known.ids.push(*id);
+            known.vectors.push(vector.clone());
+        }
+        log::debug!(
+            "Computing {} nearest neighbours ({}) of {} tiles",
+            k,
+            metric,
+            known.len()
+        );
+        let neighbours = known.nearest_neighbours(k, metric);
+
+        self.db.execute("BEGIN")?;
+        let mut delete = self.db.prepare("DELETE FROM distances WHERE tile1 = ?")?;
+        for id in &known.ids {
+            delete.reset()?;
+            delete.bind((1, *id as i64))?;
+            delete.next()?;
+        }
+        let statement =
+            "INSERT OR REPLACE INTO distances (tile1, tile2, distance) VALUES (?, ?, ?)";
+        let mut statement = self.db.prepare(statement)?;
+        for (tile1, tile2, distance) in &neighbours {
+            statement.reset()?;
+            statement.bind((1, *tile1 as i64))?;
+            statement.bind((2, *tile2 as i64))?;
+            statement.bind((3, *distance))?;
+            if statement.next()? != State::Done {
+                self.db.execute("ROLLBACK")?;
+                bail!("Failed insert");
+            }
+        }
+        self.db.execute("COMMIT")?;
+        Ok(neighbours.len() as u64)
+    }
+
+    /// The stored neighbours of a tile, closest first.
+    pub fn neighbours(&self, pos: (u64, u64), level: u64) -> Result<Vec<Neighbour>> {
+        let tile = match self.tile_id(pos, level)? {
+            Some(id) => id,
+            None => bail!("No tile at {:?} on level {}", pos, level),
+        };
+        self.neighbours_of(tile)
+    }
+
+    pub fn neighbours_of(&self, tile: u64) -> Result<Vec<Neighbour>> {
+        let statement = "SELECT d.tile2, t.x, t.y, t.level, d.distance
+            from distances d JOIN tiles t ON t.id = d.tile2 WHERE
+                d.tile1 = ?
+            ORDER BY d.distance
+        ";
+        let mut statement = self.db.prepare(statement)?;
+        statement.bind((1, tile as i64))?;
+
+        let mut neighbours = Vec::new();
+        while statement.next()? == State::Row {
+            neighbours.push(Neighbour {
+                tile: statement.read::<i64, _>(0)? as u64,
+                pos: (
+                    statement.read::<i64, _>(1)? as u64,
+                    statement.read::<i64, _>(2)? as u64,
+                ),
+                level: statement.read::<i64, _>(3)? as u64,
+                distance: statement.read::<f64, _>(4)?,
+            });
+        }
+        Ok(neighbours)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Result;
+    use crate::database::testing::test_db;
+    use crate::{Embeddings, Metric};
+
+    #[test]
+    fn neighbours() -> Result<()> {
+        let (db, path) = test_db("neighbours")?;
+        let ids: Vec<u64> = [(0, 0), (1, 0), (1, 1)]
+            .iter()
+            .map(|p| db.tile_id(*p, 1).map(|id| id.unwrap()))
+            .collect::<Result<_>>()?;
+        let csv = format!(
+            "{},0,0\n{},1,0\n{},3,0\n12345,0,0\n",
+            ids[0], ids[1], ids[2]
+        );
+        let embeddings = Embeddings::from_csv(csv.as_bytes())?;
+
+        let count = db.write_neighbours(&embeddings, 2, Metric::L2)?;
+        assert_eq!(count, 6);
+        let neighbours = db.neighbours((0, 0), 1)?;
+        assert_eq!(neighbours.len(), 2);
+        assert_eq!(neighbours[0].pos, (1, 0));
+        assert_eq!(neighbours[1].distance, 3.0);
+
+        db.write_neighbours(&embeddings, 1, Metric::L2)?;
+        assert_eq!(db.neighbours((1, 1), 1)?.len(), 1);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
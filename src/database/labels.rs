@@ -0,0 +1,215 @@
+use crate::{Database, TileLabel};
+use anyhow::{bail, Result};
+use sqlite::State;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub tile: u64,
+    pub pos: (u64, u64),
+    pub level: u64,
+    pub label: TileLabel,
+    pub source: String,
+    pub unix_time: u64,
+    pub nn_order: Option<u64>,
+    pub undo: bool,
+}
+
+const LABEL_COLUMNS: &str =
+    "l.tile, t.x, t.y, t.level, l.label, l.source, l.unix_time, l.nn_order, l.undo";
+
+fn read_label(statement: &sqlite::Statement) -> Result<Label> {
+    let label = statement.read::<i64, _>(4)?;
+    Ok(Label {
+        tile: statement.read::<i64, _>(0)? as u64,
+        pos: (
+            statement.read::<i64, _>(1)? as u64,
+            statement.read::<i64, _>(2)? as u64,
+        ),
+        level: statement.read::<i64, _>(3)? as u64,
+        label: TileLabel::try_from(label as u8)?,
+        source: statement.read::<String, _>(5)?,
+        unix_time: statement.read::<i64, _>(6)? as u64,
+        nn_order: statement.read::<Option<i64>, _>(7)?.map(|o| o as u64),
+        undo: statement.read::<i64, _>(8)? != 0,
+    })
+}
+
+impl Database {
+    pub fn tile_id(&self, pos: (u64, u64), level: u64) -> Result<Option<u64>> {
+        let (x, y) = pos;
+        let statement = "SELECT id from tiles WHERE
+            x = ? AND
+            y = ? AND
+            level = ?
+        ";
+        let mut statement = self.db.prepare(statement)?;
+        statement.bind((1, x as i64))?;
+        statement.bind((2, y as i64))?;
+        statement.bind((3, level as i64))?;
+        match statement.next()? {
+            State::Row => Ok(Some(statement.read::<i64, _>(0)? as u64)),
+            State::Done => Ok(None),
+        }
+    }
+
+    pub fn add_label(
+        &self,
+        pos: (u64, u64),
+        level: u64,
+        label: TileLabel,
+        source: &str,
+    ) -> Result<()> {
+        self.check_writeable()?;
+        let tile = match self.tile_id(pos, level)? {
+            Some(id) => id,
+            None => bail!("No tile at {:?} on level {}", pos, level),
+        };
+        let unix_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let statement = "INSERT INTO labels (tile, label, source, unix_time)
+            VALUES (?, ?, ?, ?)
+        ";
+        let mut statement = self.db.prepare(statement)?;
+        statement.bind((1, tile as i64))?;
+        statement.bind((2, label as u8 as i64))?;
+        statement.bind((3, source))?;
+        statement.bind((4, unix_time as i64))?;
+        match statement.next()? {
+            State::Done => Ok(()),
+            _ => bail!("Failed insert"),
+        }
+    }
+
+    pub fn list_labels(&self, pos: (u64, u64), level: u64) -> Result<Vec<Label>> {
+        let (x, y) = pos;
+        let statement = format!(
+            "SELECT {} from labels l JOIN tiles t ON t.id = l.tile WHERE
+                t.x = ? AND
+                t.y = ? AND
+                t.level = ?
+            ORDER BY l.unix_time, l.rowid
+            ",
+            LABEL_COLUMNS
+        );
+        let mut statement = self.db.prepare(statement)?;
+        statement.bind((1, x as i64))?;
+        statement.bind((2, y as i64))?;
+        statement.bind((3, level as i64))?;
+
+        let mut labels = Vec::new();
+        while statement.next()? == State::Row {
+            labels.push(read_label(&statement)?);
+        }
+        Ok(labels)
+    }
+
+    pub fn list_level_labels(&self, level: u64) -> Result<Vec<Label>> {
+        let statement = format!(
+            "SELECT {} from labels l JOIN tiles t ON t.id = l.tile WHERE
+                t.level = ?
+            ORDER BY l.unix_time, l.rowid
+            ",
+            LABEL_COLUMNS
+        );
+        let mut statement = self.db.prepare(statement)?;
+        statement.bind((1, level as i64))?;
+
+        let mut labels = Vec::new();
+        while statement.next()? == State::Row {
+            labels.push(read_label(&statement)?);
+        }
+        Ok(labels)
+    }
+
+    /// The latest label of every tile on a level, ignoring undone labels.
+    pub fn effective_labels(&self, level: u64) -> Result<HashMap<(u64, u64), TileLabel>> {
+        let labels = self.effective_label_entries(level)?;
+        Ok(labels.into_iter().map(|(pos, l)| (pos, l.label)).collect())
+    }
+
+    /// Like `effective_labels`, with the source and time of each label.
+    pub fn effective_label_entries(&self, level: u64) -> Result<HashMap<(u64, u64), Label>> {
+        let mut labels = HashMap::new();
+        for label in self.list_level_labels(level)? {
+            if label.undo {
+                continue;
+            }
+            labels.insert(label.pos, label);
+        }
+        Ok(labels)
+    }
+
+    pub fn effective_label(&self, pos: (u64, u64), level: u64) -> Result<Option<TileLabel>> {
+        let label = self
+            .list_labels(pos, level)?
+            .into_iter()
+            .rev()
+            .find(|l| !l.undo)
+            .map(|l| l.label);
+        Ok(label)
+    }
+
+    /// Marks the latest label of a tile as undone, returns false if there was nothing to undo.
+    pub fn undo_label(&self, pos: (u64, u64), level: u64) -> Result<bool> {
+        self.check_writeable()?;
+        let tile = match self.tile_id(pos, level)? {
+            Some(id) => id,
+            None => bail!("No tile at {:?} on level {}", pos, level),
+        };
+        let statement = "UPDATE labels SET undo = 1 WHERE rowid = (
+                SELECT rowid from labels WHERE
+                    tile = ? AND
+                    undo = 0
+                ORDER BY unix_time DESC, rowid DESC
+                LIMIT 1
+            )
+        ";
+        let mut statement = self.db.prepare(statement)?;
+        statement.bind((1, tile as i64))?;
+        statement.next()?;
+        Ok(self.db.change_count() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Result, TileLabel};
+    use crate::database::testing::test_db;
+
+    #[test]
+    fn effective_label() -> Result<()> {
+        let (db, path) = test_db("effective_label")?;
+        db.add_label((1, 0), 1, TileLabel::Tumor, "test")?;
+        db.add_label((1, 0), 1, TileLabel::Necrosis, "test")?;
+        db.add_label((1, 1), 1, TileLabel::Blood, "test")?;
+
+        assert_eq!(db.list_labels((1, 0), 1)?.len(), 2);
+        assert_eq!(db.effective_label((1, 0), 1)?, Some(TileLabel::Necrosis));
+        assert_eq!(db.effective_label((0, 0), 1)?, None);
+
+        let labels = db.effective_labels(1)?;
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels[&(1, 1)], TileLabel::Blood);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn undo() -> Result<()> {
+        let (db, path) = test_db("undo")?;
+        db.add_label((0, 0), 1, TileLabel::Tumor, "test")?;
+        db.add_label((0, 0), 1, TileLabel::Necrosis, "test")?;
+
+        assert!(db.undo_label((0, 0), 1)?);
+        assert_eq!(db.effective_label((0, 0), 1)?, Some(TileLabel::Tumor));
+        assert!(db.undo_label((0, 0), 1)?);
+        assert_eq!(db.effective_label((0, 0), 1)?, None);
+        assert!(!db.undo_label((0, 0), 1)?);
+        assert!(db.add_label((0, 1), 1, TileLabel::Tumor, "test").is_err());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -2,7 +2,10 @@ use anyhow::{bail, Result};
 use sqlite::Connection;
 use std::collections::HashMap;
 
-use crate::Database;
+use crate::{Database, TileCodec};
+
+/// Prefix of the metadata keys holding the properties of the original slide.
+pub const PROPERTY_PREFIX: &str = "property.";
 
 #[derive(Debug, Clone)]
 pub struct SlideData {
@@ -12,6 +15,7 @@ pub struct SlideData {
     pub height: u64,
     pub x_ppm: u64,
     pub y_ppm: u64,
+    pub codec: TileCodec,
 }
 
 fn read(db: &Connection, key: &str) -> Result<String> {
@@ -67,9 +71,15 @@ impl SlideData {
             height,
             x_ppm,
             y_ppm,
+            codec: TileCodec::default(),
         }
     }
 
+    pub fn with_codec(mut self, codec: TileCodec) -> SlideData {
+        self.codec = codec;
+        self
+    }
+
     pub fn from(db: &Connection) -> Result<SlideData> {
         Ok(SlideData {
             tile_size: read_u64(db, "tile_size")?,
@@ -78,6 +88,11 @@ impl SlideData {
             height: read_u64(db, "height")?,
             x_ppm: read_u64(db, "x_ppm")?,
             y_ppm: read_u64(db, "y_ppm")?,
+            // Databases without a codec were written as JPEG.
+            codec: match read(db, "codec") {
+                Ok(codec) => codec.parse()?,
+                Err(_) => TileCodec::default(),
+            },
         })
     }
     pub fn write_to(&self, db: &Connection) -> Result<()> {
@@ -87,6 +102,7 @@ impl SlideData {
         write_u64(db, "height", self.height)?;
         write_u64(db, "x_ppm", self.x_ppm)?;
         write_u64(db, "y_ppm", self.y_ppm)?;
+        write(db, "codec", &self.codec.to_string())?;
         Ok(())
     }
 }
@@ -113,6 +129,15 @@ impl Database {
         }
         Ok(metadata)
     }
+    /// The properties of the original slide, without the key prefix.
+    pub fn read_properties(&self) -> Result<HashMap<String, String>> {
+        let properties = self
+            .read_metadata()?
+            .into_iter()
+            .filter_map(|(k, v)| Some((k.strip_prefix(PROPERTY_PREFIX)?.to_owned(), v)))
+            .collect();
+        Ok(properties)
+    }
     pub fn write_metadata(&self, meta: HashMap<String, String>) -> Result<()> {
         for (key, value) in meta.iter() {
             self.set_meta(key, value)?;
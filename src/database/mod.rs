@@ -1,8 +1,47 @@
+mod associated;
+mod cache;
 mod database;
+mod distances;
+mod labels;
 mod meta;
 mod patches;
+mod python;
+mod sampler;
 mod tables;
 mod tiles;
+mod writer;
 
+pub(crate) use cache::TileCache;
 pub use database::Database;
-pub use meta::SlideData;
+pub use distances::Neighbour;
+pub use labels::Label;
+pub use meta::{SlideData, PROPERTY_PREFIX};
+pub use patches::magnification_mpp;
+pub use python::PySampler;
+pub use sampler::{Sample, Sampler, SamplerOptions, Scale};
+pub use writer::{TileWriter, DEFAULT_BATCH_SIZE};
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::{Database, SlideData};
+    use crate::Tile;
+    use anyhow::Result;
+    use image::{Rgb, RgbImage};
+    use std::path::PathBuf;
+
+    /// A fresh 8x8 database with tile size 4 and the tiles (0, 0), (1, 0) and (1, 1) on level 1.
+    pub fn test_db(name: &str) -> Result<(Database, PathBuf)> {
+        let file = format!("pamly_{}_{}.sqlite", name, std::process::id());
+        let path = std::env::temp_dir().join(file);
+        if path.is_file() {
+            std::fs::remove_file(&path)?;
+        }
+        let db = Database::create(&path, SlideData::new(4, 2, 8, 8, 1, 1))?;
+        for (x, y) in [(0, 0), (1, 0), (1, 1)] {
+            let mut tile = Tile::new((x, y), 1, 4);
+            tile.set_image(RgbImage::from_pixel(4, 4, Rgb([0, 0, 0])))?;
+            db.write(&tile)?;
+        }
+        Ok((db, path))
+    }
+}
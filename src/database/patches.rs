@@ -2,12 +2,32 @@ use crate::{Database, Patch};
 use anyhow::{bail, Result};
 use image::{imageops, ImageBuffer, Rgb};
 
+/// Approximate resolution in micrometres per pixel of a microscope magnification,
+/// e.g. 40x is 0.25 mpp and 20x is 0.5 mpp.
+pub fn magnification_mpp(magnification: f64) -> f64 {
+    10.0 / magnification
+}
+
 impl Database {
     pub fn read_region(&self, pos: (u64, u64), size: (u64, u64)) -> Result<Patch> {
         self.read_region_level(pos, size, self.levels() - 1)
     }
 
-    fn read_region_level(&self, coords: (u64, u64), size: (u64, u64), level: u64) -> Result<Patch> {
+    /// Reads a region of a single level without resampling.
+    /// Coordinates and size are given in pixels of that level.
+    pub fn read_region_level(
+        &self,
+        coords: (u64, u64),
+        size: (u64, u64),
+        level: u64,
+    ) -> Result<Patch> {
+        if level >= self.levels() {
+            bail!(
+                "Level {} does not exist, the slide has {} levels",
+                level,
+                self.levels()
+            );
+        }
         let tile_size = self.tile_size();
         let start = (
             coords.0.div_euclid(tile_size),
@@ -51,24 +71,68 @@ impl Database {
         Ok(patch)
     }
 
+    /// Reads a region of the full resolution level and resizes it to the target size.
+    /// The x and y axes are scaled independently, the level is chosen from the larger factor.
     pub fn read_region_scaled(
         &self,
         coords: (u64, u64),
         size: (u64, u64),
         target_size: (u64, u64),
     ) -> Result<Patch> {
-        let max_level = self.levels() - 1;
         let scaling_factor_x = target_size.0 as f64 / size.0 as f64;
         let scaling_factor_y = target_size.1 as f64 / size.1 as f64;
-        if (scaling_factor_x - scaling_factor_y).abs() > 1e-3 {
-            bail!("Scaling from {:?} to {:?} not supported", size, target_size);
+        let scaling_factor = f64::max(scaling_factor_x, scaling_factor_y);
+        self.read_resampled(coords, size, target_size, scaling_factor)
+    }
+
+    /// Like `read_region_scaled`, but keeps the aspect ratio of the region.
+    /// The scaled region is centred in the target size and padded with white.
+    pub fn read_region_fit(
+        &self,
+        coords: (u64, u64),
+        size: (u64, u64),
+        target_size: (u64, u64),
+    ) -> Result<Patch> {
+        let scaling_factor_x = target_size.0 as f64 / size.0 as f64;
+        let scaling_factor_y = target_size.1 as f64 / size.1 as f64;
+        let scaling_factor = f64::min(scaling_factor_x, scaling_factor_y);
+        let scaled_size = (
+            ((size.0 as f64 * scaling_factor).round() as u64).clamp(1, target_size.0),
+            ((size.1 as f64 * scaling_factor).round() as u64).clamp(1, target_size.1),
+        );
+        let patch = self.read_resampled(coords, size, scaled_size, scaling_factor)?;
+        if patch.is_empty() || scaled_size == target_size {
+            return Ok(patch);
         }
-        let level_change = scaling_factor_x.log2().ceil() as i64;
+
+        let white = Rgb([255, 255, 255]);
+        let mut padded = ImageBuffer::from_pixel(target_size.0 as u32, target_size.1 as u32, white);
+        let dx = (target_size.0 - scaled_size.0) / 2;
+        let dy = (target_size.1 - scaled_size.1) / 2;
+        imageops::replace(&mut padded, patch.image()?, dx as i64, dy as i64);
+        let mut result = Patch::new(coords, patch.level());
+        result.set_image(padded);
+        Ok(result)
+    }
+
+    /// Reads the region from the level matching the scaling factor and resizes it to the target size.
+    fn read_resampled(
+        &self,
+        coords: (u64, u64),
+        size: (u64, u64),
+        target_size: (u64, u64),
+        scaling_factor: f64,
+    ) -> Result<Patch> {
+        let max_level = self.levels() - 1;
+        let level_change = scaling_factor.log2().ceil() as i64;
         let level = (max_level as i64 + level_change).clamp(0, max_level as i64) as u64;
         let scale = 2u64.pow((max_level - level) as u32);
 
         let rel_coords = (coords.0.div_euclid(scale), coords.1.div_euclid(scale));
-        let rel_size = (size.0.div_euclid(scale), size.1.div_euclid(scale));
+        let rel_size = (
+            std::cmp::max(size.0.div_euclid(scale), 1),
+            std::cmp::max(size.1.div_euclid(scale), 1),
+        );
 
         let patch = self.read_region_level(rel_coords, rel_size, level)?;
 
@@ -89,6 +153,54 @@ impl Database {
         Ok(result)
     }
 
+    /// Resolution of the full resolution level in micrometres per pixel.
+    pub fn mpp(&self) -> Result<(f64, f64)> {
+        let (x_ppm, y_ppm) = (self.data.x_ppm, self.data.y_ppm);
+        if x_ppm == 0 || y_ppm == 0 {
+            bail!("The resolution of the slide is unknown");
+        }
+        Ok((1e6 / x_ppm as f64, 1e6 / y_ppm as f64))
+    }
+
+    /// Reads a region given in micrometres from the top left of the slide,
+    /// resampled to `mpp` micrometres per pixel, e.g. 0.5 for roughly 20x magnification.
+    pub fn read_region_microns(
+        &self,
+        origin: (f64, f64),
+        size: (f64, f64),
+        mpp: f64,
+    ) -> Result<Patch> {
+        if mpp <= 0.0 || size.0 <= 0.0 || size.1 <= 0.0 {
+            bail!("Invalid region size {:?} at {} mpp", size, mpp);
+        }
+        if origin.0 < 0.0 || origin.1 < 0.0 {
+            bail!("Region at {:?} starts outside of the slide", origin);
+        }
+        let (mpp_x, mpp_y) = self.mpp()?;
+        let to_pixels = |v: f64, mpp: f64| std::cmp::max((v / mpp).round() as u64, 1);
+        let coords = (
+            (origin.0 / mpp_x).round() as u64,
+            (origin.1 / mpp_y).round() as u64,
+        );
+        let pixels = (to_pixels(size.0, mpp_x), to_pixels(size.1, mpp_y));
+        let target_size = (to_pixels(size.0, mpp), to_pixels(size.1, mpp));
+
+        // The level has to resolve the finer of both axes.
+        let scaling_factor = f64::max(mpp_x / mpp, mpp_y / mpp);
+        self.read_resampled(coords, pixels, target_size, scaling_factor)
+    }
+
+    /// Like `read_region_microns`, with the region given by its centre.
+    pub fn read_region_microns_centered(
+        &self,
+        center: (f64, f64),
+        size: (f64, f64),
+        mpp: f64,
+    ) -> Result<Patch> {
+        let origin = (center.0 - size.0 / 2.0, center.1 - size.1 / 2.0);
+        self.read_region_microns(origin, size, mpp)
+    }
+
     pub fn thumbnail(&self, target_size: u64) -> Result<Patch> {
         let pos = (0, 0);
         let w = self.width();
@@ -103,3 +215,97 @@ impl Database {
         self.read_region_scaled(pos, (w, h), (tx, ty))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::magnification_mpp;
+    use crate::database::testing::test_db;
+    use crate::Tile;
+    use anyhow::Result;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn read_region_level() -> Result<()> {
+        let (db, path) = test_db("region_level")?;
+        let patch = db.read_region_level((2, 2), (4, 4), 1)?;
+        assert_eq!(patch.coords(), (2, 2));
+        assert_eq!(patch.level(), 1);
+        let image = patch.image()?;
+        assert_eq!(image.dimensions(), (4, 4));
+        assert_eq!(image.get_pixel(0, 0), &Rgb([0, 0, 0]));
+        assert_eq!(image.get_pixel(3, 3), &Rgb([0, 0, 0]));
+        // Tile (0, 1) does not exist and is filled with white.
+        assert_eq!(image.get_pixel(0, 3), &Rgb([255, 255, 255]));
+
+        assert!(db.read_region_level((0, 0), (4, 4), 0)?.is_empty());
+        assert!(db.read_region_level((0, 0), (4, 4), 2).is_err());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn read_region_scaled() -> Result<()> {
+        let (mut db, path) = test_db("region_scaled")?;
+        let mut tile = Tile::new((0, 0), 0, 4);
+        tile.set_image(RgbImage::from_pixel(4, 4, Rgb([0, 0, 0])))?;
+        db.write(&tile)?;
+
+        // Only x is scaled down, so the full resolution level is read.
+        let patch = db.read_region_scaled((0, 0), (8, 8), (4, 8))?;
+        assert_eq!(patch.level(), 1);
+        assert_eq!(patch.image()?.dimensions(), (4, 8));
+        let patch = db.read_region_scaled((0, 0), (8, 4), (2, 2))?;
+        assert_eq!(patch.level(), 0);
+        assert_eq!(patch.image()?.dimensions(), (2, 2));
+
+        let patch = db.read_region_fit((0, 0), (8, 4), (4, 4))?;
+        assert_eq!(patch.level(), 0);
+        let image = patch.image()?;
+        assert_eq!(image.dimensions(), (4, 4));
+        assert_eq!(image.get_pixel(0, 0), &Rgb([255, 255, 255]));
+        assert_eq!(image.get_pixel(0, 1), &Rgb([0, 0, 0]));
+        assert_eq!(image.get_pixel(3, 2), &Rgb([0, 0, 0]));
+        assert_eq!(image.get_pixel(3, 3), &Rgb([255, 255, 255]));
+
+        // Rounding up the thumbnail size gives different factors for odd aspect ratios.
+        db.data.width = 7;
+        assert_eq!(db.thumbnail(3)?.image()?.dimensions(), (3, 3));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn read_region_microns() -> Result<()> {
+        let (mut db, path) = test_db("region_microns")?;
+        db.data.x_ppm = 0;
+        assert!(db.read_region_microns((0.0, 0.0), (8.0, 8.0), 0.5).is_err());
+
+        db.data.x_ppm = 1_000_000;
+        db.data.y_ppm = 1_000_000;
+        assert_eq!(db.mpp()?, (1.0, 1.0));
+        let mut tile = Tile::new((0, 0), 0, 4);
+        tile.set_image(RgbImage::from_pixel(4, 4, Rgb([0, 0, 0])))?;
+        db.write(&tile)?;
+
+        // 2 mpp is exactly level 0, 4 mpp is read from level 0 and downscaled.
+        let patch = db.read_region_microns((0.0, 0.0), (8.0, 8.0), 2.0)?;
+        assert_eq!(patch.level(), 0);
+        assert_eq!(patch.image()?.dimensions(), (4, 4));
+        assert_eq!(patch.image()?.get_pixel(3, 3), &Rgb([0, 0, 0]));
+        let patch = db.read_region_microns((0.0, 0.0), (8.0, 8.0), 4.0)?;
+        assert_eq!(patch.level(), 0);
+        assert_eq!(patch.image()?.dimensions(), (2, 2));
+
+        let patch = db.read_region_microns_centered((4.0, 2.0), (4.0, 4.0), 1.0)?;
+        assert_eq!(patch.level(), 1);
+        assert_eq!(patch.coords(), (2, 0));
+        assert_eq!(patch.image()?.dimensions(), (4, 4));
+        assert!(db
+            .read_region_microns_centered((1.0, 1.0), (4.0, 4.0), 1.0)
+            .is_err());
+
+        assert_eq!(magnification_mpp(20.0), 0.5);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
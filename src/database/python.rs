@@ -0,0 +1,203 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::{pyclass, pymethods, Bound, Py, PyAny, PyRef, PyResult, Python};
+use std::{collections::HashMap, path::PathBuf};
+
+use super::{Sampler, SamplerOptions, Scale};
+use crate::types::{to_numpy, TileLabel};
+use crate::{Database, Patch, Tile};
+
+/// Python iterator over the samples of a `Sampler`, yielding (image, coords, label).
+#[pyclass(name = "Sampler")]
+pub struct PySampler {
+    db: Py<Database>,
+    sampler: Sampler,
+}
+
+type PySample<'py> = (Option<Bound<'py, PyAny>>, (u64, u64), Option<TileLabel>);
+
+#[pymethods]
+impl PySampler {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+    fn __next__<'py>(&mut self, py: Python<'py>) -> PyResult<Option<PySample<'py>>> {
+        let db = self.db.borrow(py);
+        let Some(sample) = self.sampler.next_sample(&db)? else {
+            return Ok(None);
+        };
+        let image = match sample.patch.image() {
+            Ok(image) => Some(to_numpy(py, image)?),
+            Err(_) => None,
+        };
+        Ok(Some((image, sample.coords, sample.label)))
+    }
+    #[getter(tissue_tiles)]
+    fn py_tissue_tiles(&self) -> usize {
+        self.sampler.tissue_tiles()
+    }
+}
+
+#[pymethods]
+impl Database {
+    #[staticmethod]
+    #[pyo3(name = "open")]
+    fn py_open(path: PathBuf) -> PyResult<Database> {
+        let db = Database::open(&path)?;
+        Ok(db)
+    }
+
+    #[getter(tile_size)]
+    fn py_tile_size(&self) -> u64 {
+        self.tile_size()
+    }
+    #[getter(codec)]
+    fn py_codec(&self) -> String {
+        self.codec().to_string()
+    }
+    #[getter(levels)]
+    fn py_levels(&self) -> u64 {
+        self.levels()
+    }
+    #[getter(width)]
+    fn py_width(&self) -> u64 {
+        self.width()
+    }
+    #[getter(height)]
+    fn py_height(&self) -> u64 {
+        self.height()
+    }
+
+    #[getter(mpp)]
+    fn py_mpp(&self) -> PyResult<(f64, f64)> {
+        let mpp = self.mpp()?;
+        Ok(mpp)
+    }
+
+    /// Keeps up to `tiles` decoded tiles in memory, 0 disables the cache.
+    /// The cache is bounded by the number of tiles, each takes tile_size * tile_size * 3 bytes.
+    #[pyo3(name = "set_cache_size")]
+    fn py_set_cache_size(&self, tiles: usize) {
+        self.set_cache_size(tiles)
+    }
+    #[pyo3(name = "clear_cache")]
+    fn py_clear_cache(&self) {
+        self.clear_cache()
+    }
+    #[pyo3(name = "cache_stats")]
+    fn py_cache_stats(&self) -> (u64, u64) {
+        self.cache_stats()
+    }
+
+    #[pyo3(name = "read")]
+    fn py_read(&self, pos: (u64, u64), level: u64) -> PyResult<Tile> {
+        let tile = self.read(pos, level)?;
+        Ok(tile)
+    }
+    #[pyo3(name = "read_many")]
+    fn py_read_many(&self, start: (u64, u64), end: (u64, u64), level: u64) -> PyResult<Vec<Tile>> {
+        let tiles = self.read_many(start, end, level)?;
+        Ok(tiles)
+    }
+    #[pyo3(name = "read_region")]
+    fn py_read_region(&self, pos: (u64, u64), size: (u64, u64)) -> PyResult<Patch> {
+        let patch = self.read_region(pos, size)?;
+        Ok(patch)
+    }
+    #[pyo3(name = "read_region_level")]
+    fn py_read_region_level(
+        &self,
+        coords: (u64, u64),
+        size: (u64, u64),
+        level: u64,
+    ) -> PyResult<Patch> {
+        let patch = self.read_region_level(coords, size, level)?;
+        Ok(patch)
+    }
+    #[pyo3(name = "read_region_scaled")]
+    fn py_read_region_scaled(
+        &self,
+        coords: (u64, u64),
+        size: (u64, u64),
+        target_size: (u64, u64),
+    ) -> PyResult<Patch> {
+        let patch = self.read_region_scaled(coords, size, target_size)?;
+        Ok(patch)
+    }
+    #[pyo3(name = "read_region_fit")]
+    fn py_read_region_fit(
+        &self,
+        coords: (u64, u64),
+        size: (u64, u64),
+        target_size: (u64, u64),
+    ) -> PyResult<Patch> {
+        let patch = self.read_region_fit(coords, size, target_size)?;
+        Ok(patch)
+    }
+    #[pyo3(name = "read_region_microns")]
+    fn py_read_region_microns(
+        &self,
+        origin: (f64, f64),
+        size: (f64, f64),
+        mpp: f64,
+    ) -> PyResult<Patch> {
+        let patch = self.read_region_microns(origin, size, mpp)?;
+        Ok(patch)
+    }
+    #[pyo3(name = "read_region_microns_centered")]
+    fn py_read_region_microns_centered(
+        &self,
+        center: (f64, f64),
+        size: (f64, f64),
+        mpp: f64,
+    ) -> PyResult<Patch> {
+        let patch = self.read_region_microns_centered(center, size, mpp)?;
+        Ok(patch)
+    }
+    #[pyo3(name = "thumbnail")]
+    fn py_thumbnail(&self, target_size: u64) -> PyResult<Patch> {
+        let patch = self.thumbnail(target_size)?;
+        Ok(patch)
+    }
+    #[pyo3(name = "sampler")]
+    #[pyo3(signature = (size, level=None, mpp=None, grid=false, labels=None, stratified=false, seed=0, count=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn py_sampler(
+        slf: Bound<'_, Self>,
+        size: (u64, u64),
+        level: Option<u64>,
+        mpp: Option<f64>,
+        grid: bool,
+        labels: Option<Vec<TileLabel>>,
+        stratified: bool,
+        seed: u64,
+        count: Option<u64>,
+    ) -> PyResult<PySampler> {
+        let mut options = SamplerOptions::new(size)
+            .with_grid(grid)
+            .with_labels(labels.unwrap_or_default())
+            .with_stratified(stratified)
+            .with_seed(seed);
+        options.count = count;
+        options.scale = match (level, mpp) {
+            (Some(_), Some(_)) => return Err(PyValueError::new_err("Pass either level or mpp")),
+            (Some(level), None) => Some(Scale::Level(level)),
+            (None, Some(mpp)) => Some(Scale::Mpp(mpp)),
+            (None, None) => None,
+        };
+        let sampler = slf.borrow().sampler(options)?;
+        Ok(PySampler {
+            db: slf.unbind(),
+            sampler,
+        })
+    }
+    #[pyo3(name = "read_metadata")]
+    fn py_read_metadata(&self) -> PyResult<HashMap<String, String>> {
+        let metadata = self.read_metadata()?;
+        Ok(metadata)
+    }
+    #[pyo3(name = "list_tiles")]
+    fn py_list_tiles(&self, level: u64) -> PyResult<Vec<(u64, u64)>> {
+        let tiles = self.list_tiles(level)?;
+        Ok(tiles)
+    }
+}
@@ -0,0 +1,329 @@
+use anyhow::{bail, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::types::TileLabel;
+use crate::{Database, Patch};
+
+/// Resolution the sampled patches are read at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scale {
+    /// A pyramid level, read without resampling
+    Level(u64),
+    /// Micrometres per pixel
+    Mpp(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamplerOptions {
+    /// Size of the patches in pixels of the target scale
+    pub size: (u64, u64),
+    /// Target scale, default is the highest resolution level
+    pub scale: Option<Scale>,
+    /// Walk a grid of non-overlapping patches instead of sampling randomly
+    pub grid: bool,
+    /// Only sample tissue with one of these effective labels, empty allows all tiles
+    pub labels: Vec<TileLabel>,
+    /// Pick every label with the same probability instead of every tile
+    pub stratified: bool,
+    pub seed: u64,
+    /// Stop after this many patches, random sampling is endless otherwise
+    pub count: Option<u64>,
+}
+
+impl SamplerOptions {
+    pub fn new(size: (u64, u64)) -> SamplerOptions {
+        SamplerOptions {
+            size,
+            scale: None,
+            grid: false,
+            labels: Vec::new(),
+            stratified: false,
+            seed: 0,
+            count: None,
+        }
+    }
+    pub fn with_scale(mut self, scale: Scale) -> SamplerOptions {
+        self.scale = Some(scale);
+        self
+    }
+    pub fn with_grid(mut self, grid: bool) -> SamplerOptions {
+        self.grid = grid;
+        self
+    }
+    pub fn with_labels(mut self, labels: Vec<TileLabel>) -> SamplerOptions {
+        self.labels = labels;
+        self
+    }
+    pub fn with_stratified(mut self, stratified: bool) -> SamplerOptions {
+        self.stratified = stratified;
+        self
+    }
+    pub fn with_seed(mut self, seed: u64) -> SamplerOptions {
+        self.seed = seed;
+        self
+    }
+    pub fn with_count(mut self, count: u64) -> SamplerOptions {
+        self.count = Some(count);
+        self
+    }
+}
+
+pub struct Sample {
+    pub patch: Patch,
+    /// Top left corner in pixels of the highest resolution level
+    pub coords: (u64, u64),
+    /// Effective label of the tile at the centre of the patch
+    pub label: Option<TileLabel>,
+}
+
+/// Draws patches from the tissue of a slide, i.e. the stored tiles of the highest resolution level.
+/// The sampler does not borrow the database, so it can be kept next to it.
+pub struct Sampler {
+    options: SamplerOptions,
+    tile_size: u64,
+    /// Size of a patch in pixels of the highest resolution level
+    footprint: (u64, u64),
+    tissue: HashMap<(u64, u64), Option<TileLabel>>,
+    tiles: Vec<(u64, u64)>,
+    /// Tissue tiles grouped by label, ordered by label
+    strata: Vec<Vec<(u64, u64)>>,
+    rng: StdRng,
+    emitted: u64,
+    grid_index: u64,
+}
+
+impl Database {
+    pub fn sampler(&self, options: SamplerOptions) -> Result<Sampler> {
+        let max_level = self.levels() - 1;
+        let (w, h) = options.size;
+        if w == 0 || h == 0 {
+            bail!("Invalid patch size {:?}", options.size);
+        }
+        let footprint = match options.scale {
+            None => (w, h),
+            Some(Scale::Level(level)) => {
+                if level > max_level {
+                    bail!(
+                        "Level {} does not exist, the slide has {} levels",
+                        level,
+                        self.levels()
+                    );
+                }
+                let scale = 2u64.pow((max_level - level) as u32);
+                (w * scale, h * scale)
+            }
+            Some(Scale::Mpp(mpp)) => {
+                if mpp <= 0.0 {
+                    bail!("Invalid resolution {} mpp", mpp);
+                }
+                let (mpp_x, mpp_y) = self.mpp()?;
+                let fw = (w as f64 * mpp / mpp_x).round() as u64;
+                let fh = (h as f64 * mpp / mpp_y).round() as u64;
+                (std::cmp::max(fw, 1), std::cmp::max(fh, 1))
+            }
+        };
+
+        let labels = self.effective_labels(max_level)?;
+        let mut tiles = self.list_tiles(max_level)?;
+        tiles.sort();
+        tiles.retain(|pos| {
+            let label = labels.get(pos);
+            options.labels.is_empty() || label.is_some_and(|l| options.labels.contains(l))
+        });
+        let mut tissue = HashMap::new();
+        let mut strata: BTreeMap<Option<u8>, Vec<(u64, u64)>> = BTreeMap::new();
+        for pos in &tiles {
+            let label = labels.get(pos).copied();
+            tissue.insert(*pos, label);
+            strata.entry(label.map(|l| l as u8)).or_default().push(*pos);
+        }
+        log::debug!(
+            "Sampling from {} tiles in {} labels",
+            tissue.len(),
+            strata.len()
+        );
+
+        Ok(Sampler {
+            rng: StdRng::seed_from_u64(options.seed),
+            options,
+            tile_size: self.tile_size(),
+            footprint,
+            tissue,
+            tiles,
+            strata: strata.into_values().collect(),
+            emitted: 0,
+            grid_index: 0,
+        })
+    }
+}
+
+impl Sampler {
+    /// Number of tissue tiles patches are drawn from.
+    pub fn tissue_tiles(&self) -> usize {
+        self.tissue.len()
+    }
+
+    /// Reads the next patch, None once the count or the grid is exhausted.
+    pub fn next_sample(&mut self, db: &Database) -> Result<Option<Sample>> {
+        if let Some(count) = self.options.count {
+            if self.emitted >= count {
+                return Ok(None);
+            }
+        }
+        let coords = match self.options.grid {
+            true => self.next_grid_position(db),
+            false => self.next_random_position(),
+        };
+        let Some(coords) = coords else {
+            return Ok(None);
+        };
+        let (cx, cy) = (
+            coords.0 + self.footprint.0 / 2,
+            coords.1 + self.footprint.1 / 2,
+        );
+        let center_tile = (cx / self.tile_size, cy / self.tile_size);
+        let label = self.tissue.get(&center_tile).copied().flatten();
+
+        let patch = self.read(db, coords)?;
+        self.emitted += 1;
+        Ok(Some(Sample {
+            patch,
+            coords,
+            label,
+        }))
+    }
+
+    pub fn iter<'a>(&'a mut self, db: &'a Database) -> impl Iterator<Item = Result<Sample>> + 'a {
+        std::iter::from_fn(move || self.next_sample(db).transpose())
+    }
+
+    fn next_random_position(&mut self) -> Option<(u64, u64)> {
+        if self.tiles.is_empty() {
+            return None;
+        }
+        let tiles = match self.options.stratified {
+            true => &self.strata[self.rng.gen_range(0..self.strata.len())],
+            false => &self.tiles,
+        };
+        let tile = tiles[self.rng.gen_range(0..tiles.len())];
+        // The centre of the patch lies somewhere on the chosen tile.
+        let cx = tile.0 * self.tile_size + self.rng.gen_range(0..self.tile_size);
+        let cy = tile.1 * self.tile_size + self.rng.gen_range(0..self.tile_size);
+        let x = cx.saturating_sub(self.footprint.0 / 2);
+        let y = cy.saturating_sub(self.footprint.1 / 2);
+        Some((x, y))
+    }
+
+    fn next_grid_position(&mut self, db: &Database) -> Option<(u64, u64)> {
+        let columns = db.width().div_ceil(self.footprint.0);
+        let rows = db.height().div_ceil(self.footprint.1);
+        while self.grid_index < columns * rows {
+            let (column, row) = (self.grid_index % columns, self.grid_index / columns);
+            self.grid_index += 1;
+            let x = column * self.footprint.0;
+            let y = row * self.footprint.1;
+            let cx = x + self.footprint.0 / 2;
+            let cy = y + self.footprint.1 / 2;
+            if self
+                .tissue
+                .contains_key(&(cx / self.tile_size, cy / self.tile_size))
+            {
+                return Some((x, y));
+            }
+        }
+        None
+    }
+
+    fn read(&self, db: &Database, coords: (u64, u64)) -> Result<Patch> {
+        let max_level = db.levels() - 1;
+        let size = self.options.size;
+        match self.options.scale {
+            None => db.read_region_level(coords, size, max_level),
+            Some(Scale::Level(level)) => {
+                let scale = 2u64.pow((max_level - level) as u32);
+                db.read_region_level((coords.0 / scale, coords.1 / scale), size, level)
+            }
+            Some(Scale::Mpp(mpp)) => {
+                let (mpp_x, mpp_y) = db.mpp()?;
+                let origin = (coords.0 as f64 * mpp_x, coords.1 as f64 * mpp_y);
+                let microns = (size.0 as f64 * mpp, size.1 as f64 * mpp);
+                db.read_region_microns(origin, microns, mpp)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SamplerOptions, Scale};
+    use crate::database::testing::test_db;
+    use crate::types::TileLabel;
+    use anyhow::Result;
+
+    #[test]
+    fn sample_tissue() -> Result<()> {
+        let (db, path) = test_db("sampler")?;
+        db.add_label((0, 0), 1, TileLabel::Tumor, "test")?;
+        db.add_label((1, 0), 1, TileLabel::Necrosis, "test")?;
+        db.add_label((1, 1), 1, TileLabel::Necrosis, "test")?;
+
+        let options = SamplerOptions::new((2, 2)).with_seed(3).with_count(50);
+        let mut sampler = db.sampler(options.clone())?;
+        assert_eq!(sampler.tissue_tiles(), 3);
+        let samples = sampler.iter(&db).collect::<Result<Vec<_>>>()?;
+        assert_eq!(samples.len(), 50);
+        for sample in &samples {
+            assert_eq!(sample.patch.size()?, (2, 2));
+            let (x, y) = (sample.coords.0 + 1, sample.coords.1 + 1);
+            assert!(x < 8 && y < 8 && !(x < 4 && y >= 4));
+        }
+        let mut again = db.sampler(options.clone())?;
+        let coords: Vec<_> = again.iter(&db).map(|s| s.unwrap().coords).collect();
+        assert_eq!(coords, samples.iter().map(|s| s.coords).collect::<Vec<_>>());
+
+        let tumor = |stratified: bool| -> Result<usize> {
+            let options = options.clone().with_count(600).with_stratified(stratified);
+            let mut sampler = db.sampler(options)?;
+            let samples = sampler.iter(&db).collect::<Result<Vec<_>>>()?;
+            Ok(samples
+                .iter()
+                .filter(|s| s.label == Some(TileLabel::Tumor))
+                .count())
+        };
+        assert!((150..250).contains(&tumor(false)?));
+        assert!((250..350).contains(&tumor(true)?));
+
+        let options = options.with_labels(vec![TileLabel::Tumor]);
+        let mut sampler = db.sampler(options)?;
+        for sample in sampler.iter(&db) {
+            let sample = sample?;
+            assert_eq!(sample.label, Some(TileLabel::Tumor));
+            assert!(sample.coords.0 < 4 && sample.coords.1 < 4);
+        }
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn sample_grid() -> Result<()> {
+        let (db, path) = test_db("sampler_grid")?;
+        let options = SamplerOptions::new((4, 4)).with_grid(true);
+        let mut sampler = db.sampler(options.clone())?;
+        let coords: Vec<_> = sampler.iter(&db).map(|s| s.unwrap().coords).collect();
+        assert_eq!(coords, vec![(0, 0), (4, 0), (4, 4)]);
+
+        let options = options.with_scale(Scale::Level(0)).with_count(1);
+        let mut sampler = db.sampler(options)?;
+        let sample = sampler.next_sample(&db)?.unwrap();
+        assert_eq!(sample.patch.level(), 0);
+        assert!(sampler.next_sample(&db)?.is_none());
+
+        let options = SamplerOptions::new((4, 4)).with_scale(Scale::Level(2));
+        assert!(db.sampler(options).is_err());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -15,7 +15,13 @@ impl Database {
 
     pub fn check_tables(&self) -> Result<()> {
         self.check_writeable()?;
-        let tables = vec!["tiles", "metadata", "labels", "distances"];
+        let tables = vec![
+            "tiles",
+            "metadata",
+            "labels",
+            "distances",
+            "associated_images",
+        ];
         for table_name in tables {
             if !self.table_exists(table_name.to_owned())? {
                 match table_name {
@@ -23,6 +29,7 @@ impl Database {
                     "metadata" => self.create_metadata_table()?,
                     "labels" => self.create_labels_table()?,
                     "distances" => self.create_distances_table()?,
+                    "associated_images" => self.create_associated_images_table()?,
                     _ => bail!("Unknown table name {}", table_name),
                 };
             }
@@ -70,6 +77,19 @@ impl Database {
         Ok(())
     }
 
+    fn create_associated_images_table(&self) -> Result<()> {
+        let query = "
+            CREATE TABLE associated_images (
+                name TEXT UNIQUE,
+                width INTEGER,
+                height INTEGER,
+                image BLOB
+            );
+        ";
+        self.db.execute(query)?;
+        Ok(())
+    }
+
     fn create_metadata_table(&self) -> Result<()> {
         let query = "
             CREATE TABLE metadata (key TEXT UNIQUE, value TEXT);
@@ -91,6 +111,10 @@ impl Database {
             );
         ";
         self.db.execute(query)?;
+        let query = "
+            CREATE INDEX idx_label_tile ON labels(tile)
+        ";
+        self.db.execute(query)?;
         Ok(())
     }
 }
@@ -1,40 +1,73 @@
 use crate::{Database, Tile};
 use anyhow::{bail, Ok, Result};
 use sqlite::State;
+use std::collections::HashSet;
+
+const SELECT_TILE: &str = "SELECT jpeg from tiles WHERE
+    x = ? AND
+    y = ? AND
+    level = ?
+";
 
 impl Database {
     pub fn read(&self, pos: (u64, u64), level: u64) -> Result<Tile> {
         let tile_size = self.tile_size();
         let mut tile = Tile::new(pos, level, tile_size);
         let (x, y) = pos;
-        let statement = "SELECT jpeg from tiles WHERE
-            x = ? AND
-            y = ? AND
-            level = ?
-        ";
-        let mut statement = self.db.prepare(statement)?;
+        let mut cache = self.cache.borrow_mut();
+        if cache.is_enabled() {
+            if let Some(image) = cache.get((x, y, level)) {
+                if let Some(image) = image {
+                    tile.set_image(image.clone())?;
+                }
+                return Ok(tile);
+            }
+        }
+        if let Some(data) = self.read_data(pos, level)? {
+            tile.set_data(data)?;
+        }
+        cache.insert((x, y, level), tile.image().ok().cloned());
+        return Ok(tile);
+    }
+
+    /// Reads the encoded image data of a tile without decoding it.
+    pub fn read_data(&self, pos: (u64, u64), level: u64) -> Result<Option<Vec<u8>>> {
+        let (x, y) = pos;
+        let mut statement = self.db.prepare(SELECT_TILE)?;
         statement.bind((1, x as i64))?;
         statement.bind((2, y as i64))?;
         statement.bind((3, level as i64))?;
-
         match statement.next()? {
-            State::Row => {
-                let data = statement.read::<Vec<u8>, _>(0)?;
-                tile.set_data(data)?;
-            }
-            _ => {}
-        };
-        return Ok(tile);
+            State::Row => Ok(Some(statement.read::<Vec<u8>, _>(0)?)),
+            State::Done => Ok(None),
+        }
     }
 
     pub fn read_many(&self, start: (u64, u64), end: (u64, u64), level: u64) -> Result<Vec<Tile>> {
         let tile_size = self.tile_size();
+        let mut cache = self.cache.borrow_mut();
+        let positions = || (start.1..end.1).flat_map(|y| (start.0..end.0).map(move |x| (x, y)));
+        let enabled = cache.is_enabled();
+
+        // Fully cached regions are served without touching the database.
+        if enabled && positions().all(|(x, y)| cache.contains((x, y, level))) {
+            let mut tiles = Vec::new();
+            for (x, y) in positions() {
+                if let Some(Some(image)) = cache.get((x, y, level)) {
+                    let mut tile = Tile::new((x, y), level, tile_size);
+                    tile.set_image(image.clone())?;
+                    tiles.push(tile);
+                }
+            }
+            return Ok(tiles);
+        }
 
         let statement = "SELECT x, y, jpeg from tiles WHERE
             x >= ? AND x < ? AND
             y >= ? AND y < ? AND
             level = ?
         ";
+        let mut returned = HashSet::new();
         let mut statement = self.db.prepare(statement)?;
         statement.bind((1, start.0 as i64))?;
         statement.bind((2, end.0 as i64))?;
@@ -44,13 +77,33 @@ impl Database {
 
         let mut tiles = Vec::new();
         while statement.next()? == State::Row {
-            let x = statement.read::<i64, _>(0)?;
-            let y = statement.read::<i64, _>(1)?;
-            let data = statement.read::<Vec<u8>, _>(2)?;
-            let mut tile = Tile::new((x as u64, y as u64), level, tile_size);
-            tile.set_data(data)?;
+            let x = statement.read::<i64, _>(0)? as u64;
+            let y = statement.read::<i64, _>(1)? as u64;
+            let mut tile = Tile::new((x, y), level, tile_size);
+            let cached = match enabled {
+                true => cache.get((x, y, level)).cloned(),
+                false => None,
+            };
+            match cached {
+                Some(Some(image)) => tile.set_image(image)?,
+                _ => {
+                    let data = statement.read::<Vec<u8>, _>(2)?;
+                    tile.set_data(data)?;
+                    cache.insert((x, y, level), tile.image().ok().cloned());
+                }
+            }
+            returned.insert((x, y));
             tiles.push(tile);
         }
+        // Only positions without a stored tile are cached as missing, returned tiles may
+        // already be evicted again if the region is larger than the cache.
+        if enabled {
+            for (x, y) in positions() {
+                if !returned.contains(&(x, y)) {
+                    cache.insert((x, y, level), None);
+                }
+            }
+        }
         Ok(tiles)
     }
 
@@ -67,28 +120,35 @@ impl Database {
         statement.bind((3, level as i64))?;
 
         statement.next()?;
+        self.invalidate(pos, level);
         Ok(())
     }
     pub fn write(&self, tile: &Tile) -> Result<()> {
         if tile.is_empty() {
             return Ok(());
         }
+        let data = tile.encode(self.codec())?;
+        self.write_data(tile, &data)
+    }
+
+    /// Writes already encoded image data at the position of the tile.
+    pub fn write_data(&self, tile: &Tile, data: &[u8]) -> Result<()> {
         let (x, y) = tile.pos();
         let level = tile.level();
         let id = tile.index();
-        let data = tile.data()?;
         let statement = "INSERT INTO tiles VALUES (?, ?, ?, ?, ?)";
         let mut statement = self.db.prepare(statement)?;
         statement.bind((1, id as i64))?;
         statement.bind((2, x as i64))?;
         statement.bind((3, y as i64))?;
         statement.bind((4, level as i64))?;
-        statement.bind((5, &data[..]))?;
+        statement.bind((5, data))?;
 
-        match statement.next()? {
-            sqlite::State::Done => return Ok(()),
-            _ => bail!("Failed insert"),
+        if statement.next()? != State::Done {
+            bail!("Failed insert");
         }
+        self.invalidate((x, y), level);
+        Ok(())
     }
 
     pub fn list_tiles(&self, level: u64) -> Result<Vec<(u64, u64)>> {
@@ -106,4 +166,26 @@ impl Database {
         }
         Ok(positions)
     }
+
+    /// Number of stored tiles per level, ordered by level.
+    pub fn tile_counts(&self) -> Result<Vec<(u64, u64)>> {
+        let statement = "SELECT level, count(*) from tiles GROUP BY level ORDER BY level";
+        let mut statement = self.db.prepare(statement)?;
+
+        let mut counts = Vec::new();
+        while statement.next()? == State::Row {
+            let level = statement.read::<i64, _>(0)?;
+            let count = statement.read::<i64, _>(1)?;
+            counts.push((level as u64, count as u64));
+        }
+        Ok(counts)
+    }
+
+    /// Total size of the encoded tiles in bytes.
+    pub fn tile_data_size(&self) -> Result<u64> {
+        let statement = "SELECT COALESCE(SUM(LENGTH(jpeg)), 0) from tiles";
+        let mut statement = self.db.prepare(statement)?;
+        statement.next()?;
+        Ok(statement.read::<i64, _>(0)? as u64)
+    }
 }
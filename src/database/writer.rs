@@ -0,0 +1,246 @@
+use crate::{Database, Tile, TileCodec};
+use anyhow::{bail, Result};
+use sqlite::{State, Statement};
+
+pub const DEFAULT_BATCH_SIZE: u64 = 256;
+
+/// Inserts tiles with a single prepared statement and commits every `batch_size` tiles.
+/// The last batch is committed by `finish`, a writer dropped without it, e.g. after an error,
+/// rolls the uncommitted tiles back.
+pub struct TileWriter<'a> {
+    db: &'a Database,
+    statement: Statement<'a>,
+    batch_size: u64,
+    pending: u64,
+    finished: bool,
+}
+
+impl<'a> TileWriter<'a> {
+    pub fn new(db: &'a Database, batch_size: u64) -> Result<TileWriter<'a>> {
+        db.check_writeable()?;
+        let statement = "INSERT INTO tiles VALUES (?, ?, ?, ?, ?)";
+        let statement = db.db.prepare(statement)?;
+        db.db.execute("BEGIN")?;
+        Ok(TileWriter {
+            db,
+            statement,
+            batch_size: std::cmp::max(batch_size, 1),
+            pending: 0,
+            finished: false,
+        })
+    }
+
+    pub fn write(&mut self, tile: &Tile) -> Result<()> {
+        if tile.is_empty() {
+            return Ok(());
+        }
+        let data = tile.encode(self.db.codec())?;
+        self.write_data(tile, &data)
+    }
+
+    /// Writes already encoded image data at the position of the tile.
+    pub fn write_data(&mut self, tile: &Tile, data: &[u8]) -> Result<()> {
+        let (x, y) = tile.pos();
+        let statement = &mut self.statement;
+        statement.reset()?;
+        statement.bind((1, tile.index() as i64))?;
+        statement.bind((2, x as i64))?;
+        statement.bind((3, y as i64))?;
+        statement.bind((4, tile.level() as i64))?;
+        statement.bind((5, data))?;
+        if statement.next()? != State::Done {
+            bail!("Failed insert");
+        }
+        statement.reset()?;
+        self.db.invalidate((x, y), tile.level());
+
+        self.pending += 1;
+        if self.pending >= self.batch_size {
+            self.db.db.execute("COMMIT; BEGIN")?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.finished = true;
+        self.db.db.execute("COMMIT")?;
+        Ok(())
+    }
+}
+
+impl Drop for TileWriter<'_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Err(e) = self.db.db.execute("ROLLBACK") {
+            log::error!("Failed to roll back tiles: {}", e);
+        }
+    }
+}
+
+impl Database {
+    pub fn writer(&self, batch_size: u64) -> Result<TileWriter<'_>> {
+        TileWriter::new(self, batch_size)
+    }
+
+    /// Re-encodes every tile with `codec` and records it in the metadata.
+    /// Tiles are read in batches of `batch_size`, all of them are converted in a single
+    /// transaction, so an interrupted run leaves the slide unchanged.
+    pub fn recompress(&mut self, codec: TileCodec, batch_size: u64) -> Result<()> {
+        self.check_writeable()?;
+        let previous = self.codec();
+        if previous == codec {
+            return Ok(());
+        }
+        self.db.execute("BEGIN")?;
+        if let Err(e) = self.recompress_tiles(previous, codec, batch_size) {
+            if let Err(e) = self.db.execute("ROLLBACK") {
+                log::error!("Failed to roll back recompression: {}", e);
+            }
+            return Err(e);
+        }
+        self.db.execute("COMMIT")?;
+
+        self.data.codec = codec;
+        self.clear_cache();
+        self.db.execute("VACUUM")?;
+        Ok(())
+    }
+
+    fn recompress_tiles(
+        &self,
+        previous: TileCodec,
+        codec: TileCodec,
+        batch_size: u64,
+    ) -> Result<()> {
+        let select = "SELECT id, jpeg from tiles WHERE id > ? ORDER BY id LIMIT ?";
+        let mut select = self.db.prepare(select)?;
+        let update = "UPDATE tiles SET jpeg = ? WHERE id = ?";
+        let mut update = self.db.prepare(update)?;
+        let mut last = i64::MIN;
+        let mut count = 0;
+        loop {
+            select.reset()?;
+            select.bind((1, last))?;
+            select.bind((2, std::cmp::max(batch_size, 1) as i64))?;
+            let mut batch = Vec::new();
+            while select.next()? == State::Row {
+                let id = select.read::<i64, _>(0)?;
+                let data = select.read::<Vec<u8>, _>(1)?;
+                batch.push((id, data));
+            }
+            let Some((id, _)) = batch.last() else {
+                break;
+            };
+            last = *id;
+
+            for (id, data) in batch.iter() {
+                let data = codec.encode(&previous.decode(data)?)?;
+                update.reset()?;
+                update.bind((1, data.as_slice()))?;
+                update.bind((2, *id))?;
+                update.next()?;
+            }
+            count += batch.len();
+            log::info!("Recompressed {} tiles", count);
+        }
+        self.set_meta("codec", &codec.to_string())
+    }
+
+    /// Switches to a write-ahead log with relaxed syncing, used while converting.
+    pub fn enable_wal(&self) -> Result<()> {
+        self.check_writeable()?;
+        self.db
+            .execute("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+        Ok(())
+    }
+
+    /// Checkpoints the write-ahead log and returns to the default rollback journal.
+    pub fn disable_wal(&self) -> Result<()> {
+        self.check_writeable()?;
+        self.db
+            .execute("PRAGMA journal_mode = DELETE; PRAGMA synchronous = FULL;")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Result;
+    use crate::database::testing::test_db;
+    use crate::{Tile, TileCodec};
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn batches() -> Result<()> {
+        let (db, path) = test_db("writer")?;
+        let mut writer = db.writer(2)?;
+        for (x, y) in [(0, 1), (0, 0), (1, 0), (0, 2)] {
+            let mut tile = Tile::new((x, y), 2, 4);
+            tile.set_image(RgbImage::from_pixel(4, 4, Rgb([10, 20, 30])))?;
+            writer.write(&tile)?;
+        }
+        writer.finish()?;
+        assert_eq!(db.list_tiles(2)?.len(), 4);
+        let pixel = db.read((0, 2), 2)?.image()?.get_pixel(0, 0)[2];
+        assert!(pixel.abs_diff(30) < 5);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn rollback_unfinished() -> Result<()> {
+        let (db, path) = test_db("writer_rollback")?;
+        let mut writer = db.writer(2)?;
+        for (x, y) in [(0, 1), (0, 0), (1, 0)] {
+            let mut tile = Tile::new((x, y), 2, 4);
+            tile.set_image(RgbImage::from_pixel(4, 4, Rgb([10, 20, 30])))?;
+            writer.write(&tile)?;
+        }
+        // Only the full batch is committed, the last tile is rolled back.
+        drop(writer);
+        assert_eq!(db.list_tiles(2)?.len(), 2);
+        assert!(db.read((1, 0), 2)?.is_empty());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn recompress() -> Result<()> {
+        let (mut db, path) = test_db("recompress")?;
+        let before = db.read((1, 1), 1)?.image()?.clone();
+        db.recompress(TileCodec::Png, 2)?;
+        assert_eq!(db.codec(), TileCodec::Png);
+        assert_eq!(db.list_tiles(1)?.len(), 3);
+        for (x, y) in db.list_tiles(1)? {
+            let data = db.read_data((x, y), 1)?.unwrap();
+            assert_eq!(image::guess_format(&data)?, image::ImageFormat::Png);
+        }
+        assert_eq!(db.read((1, 1), 1)?.image()?, &before);
+        drop(db);
+        let db = crate::Database::open(&path)?;
+        assert_eq!(db.codec(), TileCodec::Png);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn recompress_rollback() -> Result<()> {
+        let (mut db, path) = test_db("recompress_rollback")?;
+        let codec = db.codec();
+        let before = db.read_data((1, 0), 1)?;
+        db.write_data(&Tile::new((0, 1), 1, 4), b"not an image")?;
+        assert!(db.recompress(TileCodec::Png, 2).is_err());
+        assert_eq!(db.codec(), codec);
+        assert_eq!(db.read_data((1, 0), 1)?, before);
+        // No transaction is left open.
+        db.writer(1)?.finish()?;
+        drop(db);
+        let db = crate::Database::open(&path)?;
+        assert_eq!(db.codec(), codec);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::{bail, Result};
+use image::{imageops, ImageBuffer, Rgb};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::Database;
+
+/// The tiles of a slide database as a Deep Zoom pyramid.
+/// Deep Zoom levels go down to a single pixel, the levels below pamly's level 0
+/// are scaled down from its tile.
+pub struct DeepZoom<'a> {
+    db: &'a Database,
+    /// Highest Deep Zoom level, the full resolution
+    max_level: u64,
+    /// Deep Zoom level of pamly's level 0
+    base_level: u64,
+}
+
+impl<'a> DeepZoom<'a> {
+    pub fn new(db: &'a Database) -> Result<DeepZoom<'a>> {
+        let longer_side = std::cmp::max(db.width(), db.height());
+        if longer_side == 0 {
+            bail!("The slide is empty");
+        }
+        let max_level = (longer_side as f64).log2().ceil() as u64;
+        if max_level + 1 < db.levels() {
+            bail!("The slide has more levels than Deep Zoom");
+        }
+        Ok(DeepZoom {
+            db,
+            max_level,
+            base_level: max_level + 1 - db.levels(),
+        })
+    }
+
+    /// Number of Deep Zoom levels, including the 1x1 pixel level 0
+    pub fn levels(&self) -> u64 {
+        self.max_level + 1
+    }
+
+    /// File extension of the tiles, the codec of the database
+    pub fn format(&self) -> &'static str {
+        self.db.codec().extension()
+    }
+
+    pub fn xml(&self) -> String {
+        format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" ",
+                "Format=\"{}\" Overlap=\"0\" TileSize=\"{}\">\n",
+                "  <Size Width=\"{}\" Height=\"{}\"/>\n",
+                "</Image>\n"
+            ),
+            self.format(),
+            self.db.tile_size(),
+            self.db.width(),
+            self.db.height()
+        )
+    }
+
+    /// Size in pixels of a Deep Zoom level
+    pub fn level_size(&self, level: u64) -> Result<(u64, u64)> {
+        if level > self.max_level {
+            bail!("Deep Zoom level {} does not exist", level);
+        }
+        let scale = 2u64.pow((self.max_level - level) as u32);
+        Ok((
+            self.db.width().div_ceil(scale),
+            self.db.height().div_ceil(scale),
+        ))
+    }
+
+    /// Number of tiles (columns, rows) of a Deep Zoom level
+    pub fn tile_count(&self, level: u64) -> Result<(u64, u64)> {
+        let (w, h) = self.level_size(level)?;
+        let tile_size = self.db.tile_size();
+        Ok((w.div_ceil(tile_size), h.div_ceil(tile_size)))
+    }
+
+    /// Size of a tile, tiles at the right and bottom edge are cropped to the level.
+    fn tile_dimensions(&self, level: u64, pos: (u64, u64)) -> Result<(u64, u64)> {
+        let (columns, rows) = self.tile_count(level)?;
+        if pos.0 >= columns || pos.1 >= rows {
+            bail!("Tile {:?} is outside of level {}", pos, level);
+        }
+        let (w, h) = self.level_size(level)?;
+        let tile_size = self.db.tile_size();
+        Ok((
+            std::cmp::min(tile_size, w - pos.0 * tile_size),
+            std::cmp::min(tile_size, h - pos.1 * tile_size),
+        ))
+    }
+
+    /// Encoded image of a Deep Zoom tile, None if the tile has no tissue.
+    /// Stored tiles are passed through unless they have to be cropped.
+    pub fn tile(&self, level: u64, pos: (u64, u64)) -> Result<Option<Vec<u8>>> {
+        let (tw, th) = self.tile_dimensions(level, pos)?;
+
+        if level < self.base_level {
+            let (bw, bh) = self.level_size(self.base_level)?;
+            let root = self.db.read((0, 0), 0)?;
+            if root.is_empty() {
+                return Ok(None);
+            }
+            let overview = imageops::crop_imm(root.image()?, 0, 0, bw as u32, bh as u32);
+            let scaled = imageops::resize(
+                &*overview,
+                tw as u32,
+                th as u32,
+                imageops::FilterType::Lanczos3,
+            );
+            return Ok(Some(self.db.codec().encode(&scaled)?));
+        }
+
+        let pamly_level = level - self.base_level;
+        let tile_size = self.db.tile_size();
+        if tw == tile_size && th == tile_size {
+            return self.db.read_data(pos, pamly_level);
+        }
+        let tile = self.db.read(pos, pamly_level)?;
+        if tile.is_empty() {
+            return Ok(None);
+        }
+        let cropped = imageops::crop_imm(tile.image()?, 0, 0, tw as u32, th as u32).to_image();
+        Ok(Some(self.db.codec().encode(&cropped)?))
+    }
+
+    /// A white tile of the right size for tiles without tissue.
+    pub fn blank_tile(&self, level: u64, pos: (u64, u64)) -> Result<Vec<u8>> {
+        let (tw, th) = self.tile_dimensions(level, pos)?;
+        let white = ImageBuffer::from_pixel(tw as u32, th as u32, Rgb([255, 255, 255]));
+        self.db.codec().encode(&white)
+    }
+}
+
+/// Writes `out_dir/<slide>.dzi` and the tiles into `out_dir/<slide>_files/<level>/<x>_<y>.<ext>`,
+/// in the format the tiles are stored in.
+/// Tiles without tissue are skipped, or written white with `fill_missing`.
+/// Returns the path of the .dzi file.
+pub fn export_dzi(db_path: &PathBuf, out_dir: &PathBuf, fill_missing: bool) -> Result<PathBuf> {
+    let db = Database::open(db_path)?;
+    let slide = match db_path.file_stem() {
+        Some(s) => s.to_string_lossy().to_string(),
+        None => bail!("Invalid filename {}", db_path.display()),
+    };
+    let dzi = DeepZoom::new(&db)?;
+    fs::create_dir_all(out_dir)?;
+    let dzi_path = out_dir.join(format!("{}.dzi", slide));
+    fs::write(&dzi_path, dzi.xml())?;
+
+    let files = out_dir.join(format!("{}_files", slide));
+    let mut count = 0;
+    for level in 0..dzi.levels() {
+        let level_dir = files.join(level.to_string());
+        fs::create_dir_all(&level_dir)?;
+        let (columns, rows) = dzi.tile_count(level)?;
+        for y in 0..rows {
+            for x in 0..columns {
+                let data = match dzi.tile(level, (x, y))? {
+                    Some(data) => data,
+                    None if fill_missing => dzi.blank_tile(level, (x, y))?,
+                    None => continue,
+                };
+                let path = level_dir.join(format!("{}_{}.{}", x, y, dzi.format()));
+                fs::write(path, data)?;
+                count += 1;
+            }
+        }
+    }
+    log::info!("Wrote {} tiles in {} levels", count, dzi.levels());
+    Ok(dzi_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export_dzi, DeepZoom};
+    use crate::database::testing::test_db;
+    use crate::Tile;
+    use anyhow::Result;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn deep_zoom_levels() -> Result<()> {
+        let (mut db, path) = test_db("dzi")?;
+        let mut root = Tile::new((0, 0), 0, 4);
+        root.set_image(RgbImage::from_pixel(4, 4, Rgb([0, 0, 0])))?;
+        db.write(&root)?;
+        db.data.width = 7;
+
+        {
+            let dzi = DeepZoom::new(&db)?;
+            assert_eq!(dzi.levels(), 4);
+            assert_eq!(dzi.level_size(3)?, (7, 8));
+            assert_eq!(dzi.level_size(0)?, (1, 1));
+            assert!(dzi.level_size(4).is_err());
+            assert!(dzi.tile_count(99).is_err());
+            assert!(dzi.xml().contains("<Size Width=\"7\" Height=\"8\"/>"));
+
+            // Full tiles are passed through, edge tiles are cropped.
+            assert_eq!(dzi.tile(3, (0, 0))?, db.read_data((0, 0), 1)?);
+            let edge = image::load_from_memory(&dzi.tile(3, (1, 0))?.unwrap())?;
+            assert_eq!((edge.width(), edge.height()), (3, 4));
+            assert!(dzi.tile(3, (0, 1))?.is_none());
+            assert_eq!(dzi.tile(2, (0, 0))?, db.read_data((0, 0), 0)?);
+            let small = image::load_from_memory(&dzi.tile(1, (0, 0))?.unwrap())?;
+            assert_eq!((small.width(), small.height()), (2, 2));
+            assert!(dzi.tile(3, (2, 0)).is_err());
+        }
+        drop(db);
+
+        let out = std::env::temp_dir().join(format!("pamly_dzi_{}", std::process::id()));
+        let dzi_path = export_dzi(&path, &out, false)?;
+        let stem = path.file_stem().unwrap().to_string_lossy().to_string();
+        assert_eq!(dzi_path, out.join(format!("{}.dzi", stem)));
+        let files = out.join(format!("{}_files", stem));
+        assert!(files.join("0/0_0.jpg").is_file());
+        assert!(files.join("3/1_1.jpg").is_file());
+        assert!(!files.join("3/0_1.jpg").exists());
+        export_dzi(&path, &out, true)?;
+        assert!(files.join("3/0_1.jpg").is_file());
+
+        std::fs::remove_dir_all(out)?;
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,7 @@
+mod dzi;
+mod tiff;
+pub use dzi::{export_dzi, DeepZoom};
+pub use tiff::export_tiff;
+
+mod tiles;
+pub use tiles::{export_tiles, ExportOptions, MANIFEST};
@@ -0,0 +1,530 @@
+use anyhow::{bail, Result};
+use image::{Rgb, RgbImage};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use tiff::encoder::compression::{CompressionAlgorithm, Deflate};
+
+use crate::{Database, TileCodec};
+
+/// Classic TIFF offsets are 32 bit, larger files are written as BigTIFF.
+const CLASSIC_LIMIT: u64 = 0xF000_0000;
+
+// Field types
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+const LONG8: u16 = 16;
+
+// Tags
+const NEW_SUBFILE_TYPE: u16 = 254;
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC: u16 = 262;
+const IMAGE_DESCRIPTION: u16 = 270;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const X_RESOLUTION: u16 = 282;
+const Y_RESOLUTION: u16 = 283;
+const PLANAR_CONFIGURATION: u16 = 284;
+const RESOLUTION_UNIT: u16 = 296;
+const SOFTWARE: u16 = 305;
+const TILE_WIDTH: u16 = 322;
+const TILE_LENGTH: u16 = 323;
+const TILE_OFFSETS: u16 = 324;
+const TILE_BYTE_COUNTS: u16 = 325;
+const SUB_IFDS: u16 = 330;
+const YCBCR_SUBSAMPLING: u16 = 530;
+
+const COMPRESSION_JPEG: u16 = 7;
+const COMPRESSION_DEFLATE: u16 = 8;
+const PHOTOMETRIC_RGB: u16 = 2;
+const PHOTOMETRIC_YCBCR: u16 = 6;
+const RESOLUTION_UNIT_CM: u16 = 3;
+
+/// A field of an image file directory with its little endian value.
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u64,
+    value: Vec<u8>,
+}
+
+impl Entry {
+    fn shorts(tag: u16, values: &[u16]) -> Entry {
+        Entry {
+            tag,
+            kind: SHORT,
+            count: values.len() as u64,
+            value: values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        }
+    }
+    fn long(tag: u16, value: u32) -> Entry {
+        Entry {
+            tag,
+            kind: LONG,
+            count: 1,
+            value: value.to_le_bytes().to_vec(),
+        }
+    }
+    fn rational(tag: u16, numerator: u32, denominator: u32) -> Entry {
+        let mut value = numerator.to_le_bytes().to_vec();
+        value.extend(denominator.to_le_bytes());
+        Entry {
+            tag,
+            kind: RATIONAL,
+            count: 1,
+            value,
+        }
+    }
+    fn ascii(tag: u16, text: &str) -> Entry {
+        let mut value = text.as_bytes().to_vec();
+        value.push(0);
+        Entry {
+            tag,
+            kind: ASCII,
+            count: value.len() as u64,
+            value,
+        }
+    }
+}
+
+/// A little endian TIFF or BigTIFF file, written front to back.
+struct TiffFile {
+    file: BufWriter<File>,
+    position: u64,
+    big: bool,
+}
+
+impl TiffFile {
+    fn create(path: &PathBuf, big: bool) -> Result<TiffFile> {
+        let mut file = BufWriter::new(File::create(path)?);
+        // The offset of the first directory is filled in by `finish`.
+        let header: &[u8] = match big {
+            true => &[b'I', b'I', 43, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            false => &[b'I', b'I', 42, 0, 0, 0, 0, 0],
+        };
+        file.write_all(header)?;
+        Ok(TiffFile {
+            file,
+            position: header.len() as u64,
+            big,
+        })
+    }
+
+    /// Writes data at the next word boundary and returns its offset.
+    fn write(&mut self, data: &[u8]) -> Result<u64> {
+        if self.position % 2 == 1 {
+            self.file.write_all(&[0])?;
+            self.position += 1;
+        }
+        let offset = self.position;
+        if !self.big && offset + data.len() as u64 > u32::MAX as u64 {
+            bail!("The file is too large for TIFF, use BigTIFF");
+        }
+        self.file.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok(offset)
+    }
+
+    /// An entry of offsets or byte counts, 64 bit for BigTIFF.
+    fn offsets(&self, tag: u16, values: &[u64]) -> Entry {
+        let (kind, value) = match self.big {
+            true => (LONG8, values.iter().flat_map(|v| v.to_le_bytes()).collect()),
+            false => (
+                LONG,
+                values
+                    .iter()
+                    .flat_map(|v| (*v as u32).to_le_bytes())
+                    .collect(),
+            ),
+        };
+        Entry {
+            tag,
+            kind,
+            count: values.len() as u64,
+            value,
+        }
+    }
+
+    /// Writes a directory and the values that do not fit into its entries, returns its offset.
+    fn write_ifd(&mut self, mut entries: Vec<Entry>) -> Result<u64> {
+        entries.sort_by_key(|e| e.tag);
+        let (count_size, entry_size, offset_size) = match self.big {
+            true => (8, 20, 8),
+            false => (2, 12, 4),
+        };
+        let start = self.position + self.position % 2;
+        let values_start = start + count_size + entries.len() as u64 * entry_size + offset_size;
+
+        let mut ifd = Vec::new();
+        let mut values = Vec::new();
+        match self.big {
+            true => ifd.extend((entries.len() as u64).to_le_bytes()),
+            false => ifd.extend((entries.len() as u16).to_le_bytes()),
+        }
+        for entry in &entries {
+            ifd.extend(entry.tag.to_le_bytes());
+            ifd.extend(entry.kind.to_le_bytes());
+            match self.big {
+                true => ifd.extend(entry.count.to_le_bytes()),
+                false => ifd.extend((entry.count as u32).to_le_bytes()),
+            }
+            if entry.value.len() <= offset_size as usize {
+                let mut inline = entry.value.clone();
+                inline.resize(offset_size as usize, 0);
+                ifd.extend(inline);
+            } else {
+                let offset = values_start + values.len() as u64;
+                match self.big {
+                    true => ifd.extend(offset.to_le_bytes()),
+                    false => ifd.extend((offset as u32).to_le_bytes()),
+                }
+                values.extend(&entry.value);
+                if values.len() % 2 == 1 {
+                    values.push(0);
+                }
+            }
+        }
+        // No next directory, pyramid levels are linked as SubIFDs.
+        ifd.extend(vec![0; offset_size as usize]);
+        ifd.extend(values);
+        self.write(&ifd)
+    }
+
+    fn finish(mut self, first_ifd: u64) -> Result<()> {
+        self.file.flush()?;
+        let mut file = self.file.into_inner()?;
+        match self.big {
+            true => {
+                file.seek(SeekFrom::Start(8))?;
+                file.write_all(&first_ifd.to_le_bytes())?;
+            }
+            false => {
+                file.seek(SeekFrom::Start(4))?;
+                file.write_all(&(first_ifd as u32).to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sampling factors of the first component of a baseline JPEG stream.
+fn jpeg_subsampling(data: &[u8]) -> Option<(u16, u16)> {
+    let mut i = 2;
+    while i + 4 <= data.len() {
+        if data[i] != 0xFF {
+            return None;
+        }
+        let marker = data[i + 1];
+        let length = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        if matches!(marker, 0xC0..=0xC2) {
+            let sampling = *data.get(i + 11)?;
+            return Some(((sampling >> 4) as u16, (sampling & 0x0F) as u16));
+        }
+        i += 2 + length;
+    }
+    None
+}
+
+/// Deflate compressed, interleaved RGB samples of a tile.
+fn deflate(image: &RgbImage) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    Deflate::default().write_to(&mut data, image.as_raw())?;
+    Ok(data)
+}
+
+/// Whether a JPEG stream is marked as RGB by an Adobe segment with transform 0.
+fn jpeg_is_rgb(data: &[u8]) -> bool {
+    let mut i = 2;
+    while i + 4 <= data.len() && data[i] == 0xFF {
+        let marker = data[i + 1];
+        let length = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        if length < 2 {
+            return false;
+        }
+        let segment = &data[i + 4..std::cmp::min(i + 2 + length, data.len())];
+        if marker == 0xEE && segment.starts_with(b"Adobe") {
+            return segment.get(11) == Some(&0);
+        }
+        // The stream starts after the frame header
+        if matches!(marker, 0xC0..=0xC2 | 0xDA) {
+            return false;
+        }
+        i += 2 + length;
+    }
+    false
+}
+
+fn ome_xml(db: &Database, name: &str) -> String {
+    let physical_size = match db.mpp() {
+        Ok((x, y)) => format!(
+            " PhysicalSizeX=\"{}\" PhysicalSizeXUnit=\"µm\" PhysicalSizeY=\"{}\" PhysicalSizeYUnit=\"µm\"",
+            x, y
+        ),
+        Err(_) => String::new(),
+    };
+    let name = name
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+    format!(
+        concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<OME xmlns=\"http://www.openmicroscopy.org/Schemas/OME/2016-06\" ",
+            "xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" ",
+            "xsi:schemaLocation=\"http://www.openmicroscopy.org/Schemas/OME/2016-06 ",
+            "http://www.openmicroscopy.org/Schemas/OME/2016-06/ome.xsd\">",
+            "<Image ID=\"Image:0\" Name=\"{}\">",
+            "<Pixels ID=\"Pixels:0\" DimensionOrder=\"XYCZT\" Type=\"uint8\" ",
+            "SizeX=\"{}\" SizeY=\"{}\" SizeC=\"3\" SizeZ=\"1\" SizeT=\"1\" Interleaved=\"true\"{}>",
+            "<Channel ID=\"Channel:0:0\" SamplesPerPixel=\"3\"><LightPath/></Channel>",
+            "<TiffData IFD=\"0\" PlaneCount=\"1\"/>",
+            "</Pixels></Image></OME>"
+        ),
+        name,
+        db.width(),
+        db.height(),
+        physical_size
+    )
+}
+
+/// Writes the tiles of a level and returns the directory entries describing them.
+fn write_level(tiff: &mut TiffFile, db: &Database, level: u64) -> Result<Vec<Entry>> {
+    let max_level = db.levels() - 1;
+    let scale = 2u64.pow((max_level - level) as u32);
+    let tile_size = db.tile_size();
+    let width = db.width().div_ceil(scale);
+    let height = db.height().div_ceil(scale);
+    let columns = width.div_ceil(tile_size);
+    let rows = height.div_ceil(tile_size);
+
+    // JPEG tiles are copied as stored, lossless tiles are rewritten as Deflate compressed RGB.
+    let codec = db.codec();
+    let jpeg = matches!(codec, TileCodec::Jpeg { .. });
+    // Background tiles all point to the same white tile.
+    let white = RgbImage::from_pixel(tile_size as u32, tile_size as u32, Rgb([255, 255, 255]));
+    let white = match jpeg {
+        true => codec.encode(&white)?,
+        false => deflate(&white)?,
+    };
+    // Tiles passed through from the source slide may be RGB or differently subsampled,
+    // those are re-encoded like the white tile.
+    let subsampling = jpeg_subsampling(&white).unwrap_or((2, 2));
+    let mut white_offset = None;
+    let mut offsets = Vec::new();
+    let mut byte_counts = Vec::new();
+    for y in 0..rows {
+        for x in 0..columns {
+            let offset = match db.read_data((x, y), level)? {
+                Some(data) if jpeg => {
+                    let data =
+                        match jpeg_subsampling(&data) == Some(subsampling) && !jpeg_is_rgb(&data) {
+                            true => data,
+                            false => codec.encode(&codec.decode(&data)?)?,
+                        };
+                    byte_counts.push(data.len() as u64);
+                    tiff.write(&data)?
+                }
+                Some(data) => {
+                    let data = deflate(&codec.decode(&data)?)?;
+                    byte_counts.push(data.len() as u64);
+                    tiff.write(&data)?
+                }
+                None => {
+                    byte_counts.push(white.len() as u64);
+                    match white_offset {
+                        Some(offset) => offset,
+                        None => *white_offset.insert(tiff.write(&white)?),
+                    }
+                }
+            };
+            offsets.push(offset);
+        }
+    }
+
+    let (compression, photometric) = match jpeg {
+        true => (COMPRESSION_JPEG, PHOTOMETRIC_YCBCR),
+        false => (COMPRESSION_DEFLATE, PHOTOMETRIC_RGB),
+    };
+    let mut entries = vec![
+        Entry::long(IMAGE_WIDTH, width as u32),
+        Entry::long(IMAGE_LENGTH, height as u32),
+        Entry::shorts(BITS_PER_SAMPLE, &[8, 8, 8]),
+        Entry::shorts(COMPRESSION, &[compression]),
+        Entry::shorts(PHOTOMETRIC, &[photometric]),
+        Entry::shorts(SAMPLES_PER_PIXEL, &[3]),
+        Entry::shorts(PLANAR_CONFIGURATION, &[1]),
+        Entry::long(TILE_WIDTH, tile_size as u32),
+        Entry::long(TILE_LENGTH, tile_size as u32),
+        tiff.offsets(TILE_OFFSETS, &offsets),
+        tiff.offsets(TILE_BYTE_COUNTS, &byte_counts),
+    ];
+    if jpeg {
+        entries.push(Entry::shorts(
+            YCBCR_SUBSAMPLING,
+            &[subsampling.0, subsampling.1],
+        ));
+    }
+    let (x_ppm, y_ppm) = (db.data.x_ppm, db.data.y_ppm);
+    if x_ppm > 0 && y_ppm > 0 {
+        // Pixels per centimetre of this level
+        let denominator = (100 * scale) as u32;
+        entries.push(Entry::rational(X_RESOLUTION, x_ppm as u32, denominator));
+        entries.push(Entry::rational(Y_RESOLUTION, y_ppm as u32, denominator));
+        entries.push(Entry::shorts(RESOLUTION_UNIT, &[RESOLUTION_UNIT_CM]));
+    }
+    Ok(entries)
+}
+
+/// Writes a converted slide as a tiled, pyramidal OME-TIFF.
+/// The full resolution image is the first directory, the lower levels are its SubIFDs.
+/// Stored JPEG tiles are copied without decoding, PNG and WebP tiles are stored with Deflate.
+/// Missing tiles are white.
+/// BigTIFF is used if requested or if the tiles would not fit into a classic TIFF.
+pub fn export_tiff(db_path: &PathBuf, out_path: &PathBuf, bigtiff: bool) -> Result<()> {
+    let db = Database::open(db_path)?;
+    let tile_size = db.tile_size();
+    if tile_size % 16 != 0 {
+        bail!(
+            "TIFF tiles must be a multiple of 16, the tile size is {}",
+            tile_size
+        );
+    }
+    let name = match db_path.file_stem() {
+        Some(s) => s.to_string_lossy().to_string(),
+        None => bail!("Invalid filename {}", db_path.display()),
+    };
+    let big = bigtiff || db.tile_data_size()? > CLASSIC_LIMIT;
+    let mut tiff = TiffFile::create(out_path, big)?;
+
+    let max_level = db.levels() - 1;
+    let mut sub_ifds = Vec::new();
+    for level in (0..max_level).rev() {
+        let mut entries = write_level(&mut tiff, &db, level)?;
+        entries.push(Entry::long(NEW_SUBFILE_TYPE, 1));
+        sub_ifds.push(tiff.write_ifd(entries)?);
+        log::info!("Wrote level {}", level);
+    }
+    let mut entries = write_level(&mut tiff, &db, max_level)?;
+    log::info!("Wrote level {}", max_level);
+    entries.push(Entry::long(NEW_SUBFILE_TYPE, 0));
+    entries.push(Entry::ascii(IMAGE_DESCRIPTION, &ome_xml(&db, &name)));
+    entries.push(Entry::ascii(
+        SOFTWARE,
+        &format!("pamly {}", env!("CARGO_PKG_VERSION")),
+    ));
+    if !sub_ifds.is_empty() {
+        entries.push(tiff.offsets(SUB_IFDS, &sub_ifds));
+    }
+    let first_ifd = tiff.write_ifd(entries)?;
+    tiff.finish(first_ifd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export_tiff, jpeg_is_rgb, jpeg_subsampling};
+    use crate::{Database, SlideData, Tile, TileCodec};
+    use anyhow::Result;
+    use image::{Rgb, RgbImage};
+    use std::fs::File;
+    use tiff::decoder::{Decoder, DecodingResult};
+    use tiff::tags::{IfdPointer, Tag};
+
+    #[test]
+    fn export_pyramid() -> Result<()> {
+        let dir = std::env::temp_dir();
+        let db_path = dir.join(format!("pamly_tiff_{}.sqlite", std::process::id()));
+        if db_path.is_file() {
+            std::fs::remove_file(&db_path)?;
+        }
+        let db = Database::create(
+            &db_path,
+            SlideData::new(16, 2, 32, 32, 4_000_000, 4_000_000),
+        )?;
+        for (x, y, level) in [(0, 0, 1), (1, 1, 1), (0, 0, 0)] {
+            let mut tile = Tile::new((x, y), level, 16);
+            tile.set_image(RgbImage::from_pixel(16, 16, Rgb([0, 0, 0])))?;
+            db.write(&tile)?;
+        }
+        let stored = db.read_data((1, 1), 1)?.unwrap();
+        assert!(jpeg_subsampling(&stored).is_some());
+        assert!(!jpeg_is_rgb(&stored));
+        let adobe = [
+            0xFF, 0xEE, 0, 14, b'A', b'd', b'o', b'b', b'e', 0, 100, 0, 0, 0, 0, 0,
+        ];
+        assert!(jpeg_is_rgb(&[&stored[..2], &adobe, &stored[2..]].concat()));
+        drop(db);
+
+        for bigtiff in [false, true] {
+            let out = dir.join(format!(
+                "pamly_tiff_{}_{}.ome.tiff",
+                std::process::id(),
+                bigtiff
+            ));
+            export_tiff(&db_path, &out, bigtiff)?;
+
+            let mut decoder = Decoder::new(File::open(&out)?)?;
+            assert_eq!(decoder.dimensions()?, (32, 32));
+            assert_eq!(decoder.tile_count()?, 4);
+            let description = decoder.get_tag_ascii_string(Tag::ImageDescription)?;
+            assert!(description.contains("PhysicalSizeX=\"0.25\""));
+            assert!(description.contains("SizeX=\"32\""));
+            let offsets = decoder.get_tag_u64_vec(Tag::TileOffsets)?;
+            let counts = decoder.get_tag_u64_vec(Tag::TileByteCounts)?;
+            // The stored tile is copied unchanged, the missing tiles share one white tile.
+            let data = std::fs::read(&out)?;
+            let start = offsets[3] as usize;
+            assert_eq!(&data[start..start + counts[3] as usize], &stored[..]);
+            assert_eq!(offsets[1], offsets[2]);
+
+            let DecodingResult::U8(pixels) = decoder.read_image()? else {
+                panic!("Unexpected sample type");
+            };
+            assert!(pixels[0] < 8);
+            assert!(pixels[3 * 16] > 247);
+
+            let sub_ifds = decoder.get_tag_u64_vec(Tag::SubIfd)?;
+            assert_eq!(sub_ifds.len(), 1);
+            let directory = decoder.read_directory(IfdPointer(sub_ifds[0]))?;
+            let mut tags = decoder.read_directory_tags(&directory);
+            assert_eq!(tags.get_tag_u32(Tag::ImageWidth)?, 16);
+            assert_eq!(tags.get_tag_u32(Tag::NewSubfileType)?, 1);
+            std::fs::remove_file(out)?;
+        }
+        std::fs::remove_file(db_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn export_lossless() -> Result<()> {
+        let dir = std::env::temp_dir();
+        let db_path = dir.join(format!("pamly_tiff_png_{}.sqlite", std::process::id()));
+        if db_path.is_file() {
+            std::fs::remove_file(&db_path)?;
+        }
+        let data = SlideData::new(16, 1, 32, 16, 0, 0).with_codec(TileCodec::Png);
+        let db = Database::create(&db_path, data)?;
+        let mut tile = Tile::new((1, 0), 0, 16);
+        tile.set_image(RgbImage::from_pixel(16, 16, Rgb([10, 200, 30])))?;
+        db.write(&tile)?;
+        drop(db);
+
+        let out = dir.join(format!("pamly_tiff_png_{}.ome.tiff", std::process::id()));
+        export_tiff(&db_path, &out, false)?;
+        let mut decoder = Decoder::new(File::open(&out)?)?;
+        assert_eq!(decoder.get_tag_u32(Tag::Compression)?, 8);
+        assert_eq!(decoder.get_tag_u32(Tag::PhotometricInterpretation)?, 2);
+        let DecodingResult::U8(pixels) = decoder.read_image()? else {
+            panic!("Unexpected sample type");
+        };
+        assert_eq!(&pixels[..3], &[255, 255, 255]);
+        assert_eq!(&pixels[16 * 3..16 * 3 + 3], &[10, 200, 30]);
+        std::fs::remove_file(out)?;
+        std::fs::remove_file(db_path)?;
+        Ok(())
+    }
+}
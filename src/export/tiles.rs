@@ -0,0 +1,261 @@
+use anyhow::{bail, Result};
+use image::ImageFormat;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::{Database, TileLabel};
+
+pub const MANIFEST: &str = "manifest.csv";
+const MANIFEST_HEADER: &str = "path,slide,level,x,y,width,height,label,source,mpp_x,mpp_y";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportOptions {
+    /// Level to export, default is the highest resolution
+    pub level: Option<u64>,
+    /// Patch size in pixels of the level, default is the tile size
+    pub size: Option<u64>,
+    /// Distance between patches, default is the patch size
+    pub stride: Option<u64>,
+    pub format: ImageFormat,
+    /// Skip patches without a label instead of writing them to `Unlabeled`
+    pub labeled_only: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> ExportOptions {
+        ExportOptions {
+            level: None,
+            size: None,
+            stride: None,
+            format: ImageFormat::Png,
+            labeled_only: false,
+        }
+    }
+}
+
+/// Quotes a csv field if needed.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Splits a csv row written with `csv_field` into its fields.
+fn csv_fields(row: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = row.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Opens the manifest for the rows of `slide`, rows of earlier exports of the slide are removed.
+fn open_manifest(out_dir: &Path, slide: &str) -> Result<File> {
+    let path = out_dir.join(MANIFEST);
+    let mut rows = Vec::new();
+    if path.is_file() {
+        let content = fs::read_to_string(&path)?;
+        rows = content
+            .lines()
+            .skip(1)
+            .filter(|row| csv_fields(row).get(1).map(String::as_str) != Some(slide))
+            .map(str::to_owned)
+            .collect();
+    }
+    let mut file = File::create(&path)?;
+    writeln!(file, "{}", MANIFEST_HEADER)?;
+    for row in rows {
+        writeln!(file, "{}", row)?;
+    }
+    Ok(file)
+}
+
+/// Writes the patches of a slide into `out_dir/<TileLabel>/<slide>_<level>_<x>_<y>.<ext>`,
+/// with x and y in pixels of the level, and lists them in `out_dir/manifest.csv`,
+/// replacing the rows of an earlier export of the slide.
+/// Patches are labelled by the effective label of the tile at their centre.
+/// Returns the number of written patches.
+pub fn export_tiles(db_path: &PathBuf, out_dir: &PathBuf, options: &ExportOptions) -> Result<u64> {
+    let db = Database::open(db_path)?;
+    let slide = match db_path.file_stem() {
+        Some(s) => s.to_string_lossy().to_string(),
+        None => bail!("Invalid filename {}", db_path.display()),
+    };
+    let level = options.level.unwrap_or(db.levels() - 1);
+    if level >= db.levels() {
+        bail!(
+            "Level {} does not exist, the slide has {} levels",
+            level,
+            db.levels()
+        );
+    }
+    let tile_size = db.tile_size();
+    let size = options.size.unwrap_or(tile_size);
+    let stride = options.stride.unwrap_or(size);
+    if size == 0 || stride == 0 {
+        bail!("Patch size and stride must be positive");
+    }
+    let extension = match options.format.extensions_str().first() {
+        Some(e) => *e,
+        None => bail!("Unsupported format {:?}", options.format),
+    };
+    let (mpp_x, mpp_y) = match db.mpp() {
+        Ok((x, y)) => (x.to_string(), y.to_string()),
+        Err(_) => (String::new(), String::new()),
+    };
+
+    let labels = db.effective_label_entries(level)?;
+    let mut tiles = db.list_tiles(level)?;
+    tiles.sort_by_key(|(x, y)| (*y, *x));
+    fs::create_dir_all(out_dir)?;
+    let mut manifest = open_manifest(out_dir, &slide)?;
+
+    // Patches are placed on the stride grid, only those centred on a stored tile are written.
+    let positions = if size == tile_size && stride == tile_size {
+        tiles
+            .iter()
+            .map(|(x, y)| (x * tile_size, y * tile_size))
+            .collect()
+    } else {
+        let scale = 2u64.pow((db.levels() - 1 - level) as u32);
+        let width = db.width().div_ceil(scale);
+        let height = db.height().div_ceil(scale);
+        let stored: HashSet<_> = tiles.into_iter().collect();
+        let mut positions = Vec::new();
+        for y in (0..height).step_by(stride as usize) {
+            for x in (0..width).step_by(stride as usize) {
+                let center = ((x + size / 2) / tile_size, (y + size / 2) / tile_size);
+                if stored.contains(&center) {
+                    positions.push((x, y));
+                }
+            }
+        }
+        positions
+    };
+    log::info!("Exporting {} patches of {}", positions.len(), slide);
+
+    let mut count = 0;
+    for (x, y) in positions {
+        let center = ((x + size / 2) / tile_size, (y + size / 2) / tile_size);
+        let entry = labels.get(&center);
+        if entry.is_none() && options.labeled_only {
+            continue;
+        }
+        let label = entry.map(|l| l.label).unwrap_or(TileLabel::Unlabeled);
+        let source = entry.map(|l| l.source.as_str()).unwrap_or("");
+
+        let patch = db.read_region_level((x, y), (size, size), level)?;
+        if patch.is_empty() {
+            continue;
+        }
+        let label_dir = out_dir.join(label.to_string());
+        fs::create_dir_all(&label_dir)?;
+        let file_name = format!("{}_{}_{}_{}.{}", slide, level, x, y, extension);
+        let path = label_dir.join(&file_name);
+        patch.image()?.save_with_format(&path, options.format)?;
+
+        let relative = format!("{}/{}", label, file_name);
+        let row = [
+            relative,
+            slide.clone(),
+            level.to_string(),
+            x.to_string(),
+            y.to_string(),
+            size.to_string(),
+            size.to_string(),
+            label.to_string(),
+            source.to_owned(),
+            mpp_x.clone(),
+            mpp_y.clone(),
+        ];
+        let row: Vec<String> = row.iter().map(|v| csv_field(v)).collect();
+        writeln!(manifest, "{}", row.join(","))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csv_field, csv_fields, export_tiles, ExportOptions, MANIFEST};
+    use crate::database::testing::test_db;
+    use crate::TileLabel;
+    use anyhow::Result;
+
+    #[test]
+    fn export_label_folders() -> Result<()> {
+        let (db, path) = test_db("export_tiles")?;
+        db.add_label((0, 0), 1, TileLabel::Tumor, "alice, bob")?;
+        db.add_label((1, 1), 1, TileLabel::Necrosis, "test")?;
+        db.add_label((1, 1), 1, TileLabel::Tumor, "test")?;
+        drop(db);
+
+        let out = std::env::temp_dir().join(format!("pamly_export_{}", std::process::id()));
+        if out.is_dir() {
+            std::fs::remove_dir_all(&out)?;
+        }
+        let slide = path.file_stem().unwrap().to_string_lossy().to_string();
+        let count = export_tiles(&path, &out, &ExportOptions::default())?;
+        assert_eq!(count, 3);
+        assert!(out.join(format!("Tumor/{}_1_0_0.png", slide)).is_file());
+        assert!(out.join(format!("Tumor/{}_1_4_4.png", slide)).is_file());
+        assert!(out.join(format!("Unlabeled/{}_1_4_0.png", slide)).is_file());
+
+        let manifest = std::fs::read_to_string(out.join(MANIFEST))?;
+        let lines: Vec<&str> = manifest.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("path,slide,level,x,y"));
+        let row = format!("Tumor/{}_1_0_0.png,{},1,0,0,4,4,Tumor,", slide, slide);
+        assert_eq!(lines[1], format!("{}\"alice, bob\",1000000,1000000", row));
+
+        // Rows of other slides are kept, rows of an earlier export of the slide are replaced.
+        let other = "other,\"slide\"";
+        std::fs::write(
+            out.join(MANIFEST),
+            format!(
+                "{}x.png,{},1,0,0,4,4,Tumor,,,\n",
+                manifest,
+                csv_field(other)
+            ),
+        )?;
+        assert_eq!(csv_fields(lines[1])[8], "alice, bob");
+        let options = ExportOptions {
+            size: Some(2),
+            stride: Some(3),
+            labeled_only: true,
+            format: image::ImageFormat::Jpeg,
+            ..ExportOptions::default()
+        };
+        // Patches at 0, 3 and 6 in both directions, five of them centred on a tumor tile
+        assert_eq!(export_tiles(&path, &out, &options)?, 5);
+        assert_eq!(export_tiles(&path, &out, &options)?, 5);
+        assert!(out.join(format!("Tumor/{}_1_3_3.jpg", slide)).is_file());
+
+        let manifest = std::fs::read_to_string(out.join(MANIFEST))?;
+        let lines: Vec<&str> = manifest.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(csv_fields(lines[1])[1], other);
+        assert!(lines[2..].iter().all(|l| l.contains(".jpg,")));
+
+        assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+        std::fs::remove_dir_all(out)?;
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
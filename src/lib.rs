@@ -1,9 +1,17 @@
-#[cfg(feature = "convert")]
 pub mod convert;
+pub mod export;
+pub mod serve;
 
 mod database;
+pub use database::magnification_mpp;
 pub use database::Database;
+pub use database::Label;
+pub use database::Neighbour;
 pub use database::SlideData;
+pub use database::TileWriter;
+pub use database::DEFAULT_BATCH_SIZE;
+pub use database::PROPERTY_PREFIX;
+pub use database::{Sample, Sampler, SamplerOptions, Scale};
 
 pub mod types;
 pub use types::*;
@@ -15,5 +23,9 @@ fn pamly(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<types::Diagnosis>()?;
     m.add_class::<types::Stain>()?;
     m.add_class::<types::TileLabel>()?;
+    m.add_class::<types::Tile>()?;
+    m.add_class::<types::Patch>()?;
+    m.add_class::<Database>()?;
+    m.add_class::<database::PySampler>()?;
     Ok(())
 }
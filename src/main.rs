@@ -1,14 +1,24 @@
 use anyhow::{bail, Result};
 use clap::{Args, Parser, Subcommand};
-use image::ImageFormat;
+use image::{ImageFormat, RgbImage};
 use log::Level;
-use std::{collections::HashMap, fs::File, path::PathBuf};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-#[cfg(feature = "convert")]
-use pamly::convert::{convert, convert_all, downscale, Config, LockFile};
+use pamly::convert::{
+    convert, convert_all, convert_source, downscale, open_slide, resume as resume_convert, Config,
+    LockFile, SynthSlide,
+};
 
-use pamly::types::{Diagnosis, Stain, TileLabel};
-use pamly::Database;
+use pamly::export::{export_dzi, export_tiff, export_tiles, ExportOptions};
+use pamly::serve::serve;
+use pamly::types::{Diagnosis, Embeddings, Metric, Stain, TileCodec, TileLabel};
+use pamly::{Database, DEFAULT_BATCH_SIZE, PROPERTY_PREFIX};
 
 /// Pamly Command line Interface
 #[derive(Parser)]
@@ -27,25 +37,176 @@ struct Cli {
 enum Commands {
     /// Generate types files
     Types(TypesArgs),
-    #[cfg(feature = "convert")]
     /// Convert a slide to a sqlite database
     Convert(ConvertArgs),
-    #[cfg(feature = "convert")]
     /// Convert a slide to a sqlite database
     ConvertAll(ConvertAllArgs),
-    #[cfg(feature = "convert")]
     /// Downscale a slide
     Downscale(DownscaleArgs),
+    /// Re-encode the tiles of a converted slide with another codec
+    Recompress(RecompressArgs),
+    /// Generate a synthetic slide with known tissue geometry
+    Synth(SynthArgs),
+    /// Export tiles into one folder per label with a csv manifest
+    ExportTiles(ExportTilesArgs),
+    /// Export a slide as a Deep Zoom (DZI) pyramid
+    ExportDzi(ExportDziArgs),
+    /// Export a slide as a pyramidal OME-TIFF
+    ExportTiff(ExportTiffArgs),
+    /// Serve the slides of a folder over HTTP
+    Serve(ServeArgs),
     /// Generate a thumbnail from a slide
     Thumbnail(ThumbnailArgs),
     /// Extract metadata from a slide
     Metadata(MetadataArgs),
+    /// Read a region of a converted slide into an image
+    Region(RegionArgs),
+    /// Read and write tile labels
+    Label(LabelArgs),
+    /// Compute and query nearest neighbours of tiles
+    Distances(DistancesArgs),
+}
+
+#[derive(Args)]
+struct DistancesArgs {
+    #[command(subcommand)]
+    command: DistancesCommands,
+}
+
+#[derive(Subcommand)]
+enum DistancesCommands {
+    /// Import tile embeddings and store the k nearest neighbours of every tile
+    Compute(DistancesComputeArgs),
+    /// List the nearest neighbours of a tile
+    Neighbours(DistancesNeighboursArgs),
+}
+
+#[derive(Args)]
+struct DistancesComputeArgs {
+    /// The path to the slide database
+    #[arg(value_name = "Slide Path")]
+    path_str: String,
+    /// A .npy or .csv file, the first column is the tile id
+    #[arg(value_name = "Embeddings Path")]
+    embeddings: String,
+    /// Number of neighbours per tile
+    #[arg(short, default_value_t = 10)]
+    k: usize,
+    /// Distance metric, cosine or l2
+    #[arg(short, long, default_value = "cosine")]
+    metric: String,
+}
+
+#[derive(Args)]
+struct DistancesNeighboursArgs {
+    /// The path to the slide database
+    #[arg(value_name = "Slide Path")]
+    path_str: String,
+    x: u64,
+    y: u64,
+    /// Tile level, default is the highest resolution
+    #[arg(short, long)]
+    level: Option<u64>,
+}
+
+#[derive(Args)]
+struct LabelArgs {
+    #[command(subcommand)]
+    command: LabelCommands,
+}
+
+#[derive(Subcommand)]
+enum LabelCommands {
+    /// Add a label to a tile
+    Add(LabelAddArgs),
+    /// List all labels of a tile or a level
+    List(LabelListArgs),
+    /// Print the effective label of every tile on a level
+    Effective(LabelEffectiveArgs),
+    /// Undo the last label of a tile
+    Undo(LabelUndoArgs),
+}
+
+#[derive(Args)]
+struct LabelAddArgs {
+    /// The path to the slide database
+    #[arg(value_name = "Slide Path")]
+    path_str: String,
+    x: u64,
+    y: u64,
+    /// The label, e.g. Tumor
+    label: String,
+    /// Tile level, default is the highest resolution
+    #[arg(short, long)]
+    level: Option<u64>,
+    /// Who created the label
+    #[arg(short, long, default_value = "pamly")]
+    source: String,
+}
+
+#[derive(Args)]
+struct LabelListArgs {
+    /// The path to the slide database
+    #[arg(value_name = "Slide Path")]
+    path_str: String,
+    /// Only list labels of the tile at x, y
+    #[arg(long, num_args = 2, value_names = ["X", "Y"])]
+    pos: Option<Vec<u64>>,
+    /// Tile level, default is the highest resolution
+    #[arg(short, long)]
+    level: Option<u64>,
+}
+
+#[derive(Args)]
+struct LabelEffectiveArgs {
+    /// The path to the slide database
+    #[arg(value_name = "Slide Path")]
+    path_str: String,
+    /// Tile level, default is the highest resolution
+    #[arg(short, long)]
+    level: Option<u64>,
+}
+
+#[derive(Args)]
+struct LabelUndoArgs {
+    /// The path to the slide database
+    #[arg(value_name = "Slide Path")]
+    path_str: String,
+    x: u64,
+    y: u64,
+    /// Tile level, default is the highest resolution
+    #[arg(short, long)]
+    level: Option<u64>,
 }
 
 #[derive(Args)]
 struct MetadataArgs {
     #[arg(value_name = "Input Path")]
     path: String,
+    /// Print the metadata as json
+    #[arg(short, long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct RegionArgs {
+    /// The path to the slide database
+    #[arg(value_name = "Slide Path")]
+    path_str: String,
+    /// Output image, the format is taken from the extension
+    #[arg(value_name = "Output Path")]
+    out_path: String,
+    /// Position and size in pixels of the level, or in micrometres with --mpp
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    /// Pyramid level, default is the highest resolution
+    #[arg(short, long, conflicts_with = "mpp")]
+    level: Option<u64>,
+    /// Target resolution in micrometres per pixel, e.g. 0.5 for 20x
+    #[arg(short, long)]
+    mpp: Option<f64>,
 }
 
 #[derive(Args)]
@@ -62,6 +223,9 @@ struct ThumbnailArgs {
     out_path: Option<String>,
     #[arg(short, long, default_value_t = 1024)]
     size: u64,
+    /// Also extract the label and macro images of the slide
+    #[arg(short, long)]
+    associated: bool,
 }
 
 #[derive(Args)]
@@ -78,6 +242,9 @@ struct ConvertArgs {
     /// Ignore overwrite of slide and existing lock
     #[arg(short, long)]
     force: bool,
+    /// Continue an interrupted conversion
+    #[arg(short, long)]
+    resume: bool,
 }
 
 #[derive(Args)]
@@ -94,6 +261,9 @@ struct ConvertAllArgs {
     /// Ignore overwrite of slide and existing lock
     #[arg(short, long)]
     force: bool,
+    /// Also convert plain images (PNG, JPEG, ...) found in the folder
+    #[arg(long)]
+    images: bool,
 }
 
 #[derive(Args)]
@@ -101,6 +271,102 @@ struct DownscaleArgs {
     /// The path to the slide
     #[arg(value_name = "Slide Path")]
     path_str: String,
+    /// Optional config file
+    #[arg(short, long)]
+    config: Option<String>,
+}
+
+#[derive(Args)]
+struct RecompressArgs {
+    /// The path to the slide database
+    #[arg(value_name = "Slide Path")]
+    path_str: String,
+    /// The new tile codec, jpeg[:quality], png or webp
+    #[arg(long)]
+    codec: String,
+}
+
+#[derive(Args)]
+struct SynthArgs {
+    /// An image file, or a .sqlite file to convert the slide directly
+    #[arg(value_name = "Output Path")]
+    out_path: String,
+    #[arg(long, default_value_t = 8192)]
+    width: u64,
+    #[arg(long, default_value_t = 6144)]
+    height: u64,
+    /// Seed of the random tissue layout and texture
+    #[arg(short, long, default_value_t = 0)]
+    seed: u64,
+    /// Optional config file, used when converting
+    #[arg(short, long)]
+    config: Option<String>,
+    /// Overwrite an existing output file
+    #[arg(short, long)]
+    force: bool,
+}
+
+#[derive(Args)]
+struct ExportTilesArgs {
+    /// One or more slide databases
+    #[arg(value_name = "Slide Paths", required = true)]
+    paths: Vec<String>,
+    /// Output folder, default ./dataset
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Level to export, default is the highest resolution
+    #[arg(short, long)]
+    level: Option<u64>,
+    /// Patch size in pixels, default is the tile size
+    #[arg(short, long)]
+    size: Option<u64>,
+    /// Distance between patches, default is the patch size
+    #[arg(long)]
+    stride: Option<u64>,
+    /// Image format, e.g. png or jpg
+    #[arg(short, long, default_value = "png")]
+    format: String,
+    /// Skip tiles without a label
+    #[arg(long)]
+    labeled_only: bool,
+}
+
+#[derive(Args)]
+struct ExportDziArgs {
+    /// The path to the slide database
+    #[arg(value_name = "Slide Path")]
+    path_str: String,
+    /// Output folder for the .dzi file and the tiles
+    #[arg(value_name = "Output Path")]
+    out_path: String,
+    /// Write white tiles where the slide has no tissue
+    #[arg(long)]
+    fill_missing: bool,
+}
+
+#[derive(Args)]
+struct ExportTiffArgs {
+    /// The path to the slide database
+    #[arg(value_name = "Slide Path")]
+    path_str: String,
+    /// Output file, default is the slide path with the extension .ome.tiff
+    #[arg(value_name = "Output Path")]
+    out_path: Option<String>,
+    /// Always write a BigTIFF, otherwise only used for files larger than 4 GB
+    #[arg(long)]
+    bigtiff: bool,
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Folder with the slide databases
+    #[arg(value_name = "Folder")]
+    dir: String,
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+    #[arg(short, long, default_value_t = 8000)]
+    port: u16,
 }
 
 fn main() -> Result<()> {
@@ -116,13 +382,13 @@ fn main() -> Result<()> {
     simple_logger::init_with_level(level)?;
 
     match &cli.command {
-        #[cfg(feature = "convert")]
         Commands::Convert(args) => {
             let ConvertArgs {
                 path_str,
                 config,
                 output,
                 force,
+                resume,
             } = args;
             let path = PathBuf::from(path_str);
             if !path.is_file() {
@@ -136,6 +402,25 @@ fn main() -> Result<()> {
                     base_path.join("slide.sqlite").to_owned()
                 }
             };
+            let config = match config {
+                Some(s) => {
+                    let path = PathBuf::from(s);
+                    Config::from(path)?
+                }
+                None => Config::default(),
+            };
+            // A lockfile without a database is left by a conversion interrupted before the
+            // database was created, resuming it starts a new conversion.
+            let locked = LockFile::exists(&db_path)?;
+            if *resume && (db_path.is_file() || locked) {
+                if !locked {
+                    log::info!("{} is already converted", db_path.display());
+                    return Ok(());
+                }
+                log::debug!("Resume from {} to {}", &path.display(), &db_path.display());
+                resume_convert(path, db_path, &config)?;
+                return Ok(());
+            }
             if db_path.is_file() {
                 if *force {
                     log::warn!("Overwriting {}", db_path.display());
@@ -154,24 +439,17 @@ fn main() -> Result<()> {
                 }
             }
 
-            let config = match config {
-                Some(s) => {
-                    let path = PathBuf::from(s);
-                    Config::from(path)?
-                }
-                None => Config::default(),
-            };
             log::debug!("Config:\n {}", serde_json::to_string_pretty(&config)?);
             log::debug!("Convert from {} to {}", &path.display(), &db_path.display());
             convert(path, db_path, &config)?;
         }
-        #[cfg(feature = "convert")]
         Commands::ConvertAll(args) => {
             let ConvertAllArgs {
                 path_str,
                 config,
                 output,
                 force,
+                images,
             } = args;
             let path = PathBuf::from(path_str);
             if !path.is_dir() {
@@ -196,24 +474,129 @@ fn main() -> Result<()> {
                 }
                 None => Config::default(),
             };
-            convert_all(path, output_path, &config, *force)?;
+            convert_all(path, output_path, &config, *force, *images)?;
         }
 
-        #[cfg(feature = "convert")]
         Commands::Downscale(args) => {
-            let DownscaleArgs { path_str } = args;
+            let DownscaleArgs { path_str, config } = args;
             let db_path = PathBuf::from(path_str);
+            let config = match config {
+                Some(s) => Config::from(PathBuf::from(s))?,
+                None => Config::default(),
+            };
             let mut lock = LockFile::lock(&db_path, "Init")?;
             let db = Database::open_readwrite(&db_path)?;
-            downscale(&db, &mut lock)?;
+            downscale(&db, &config, &mut lock)?;
+            lock.release()?;
+        }
+        Commands::Recompress(args) => {
+            let RecompressArgs { path_str, codec } = args;
+            let codec = codec.parse::<TileCodec>()?;
+            let db_path = PathBuf::from(path_str);
+            let lock = LockFile::lock(&db_path, "Recompress")?;
+            let mut db = Database::open_readwrite(&db_path)?;
+            let size = db.tile_data_size()?;
+            db.recompress(codec, DEFAULT_BATCH_SIZE)?;
+            log::info!(
+                "Recompressed {} as {}, {} MB to {} MB",
+                db_path.display(),
+                codec,
+                size / 1_000_000,
+                db.tile_data_size()? / 1_000_000
+            );
             lock.release()?;
         }
+        Commands::ExportTiles(args) => {
+            let ExportTilesArgs {
+                paths,
+                output,
+                level,
+                size,
+                stride,
+                format,
+                labeled_only,
+            } = args;
+            let out_dir = PathBuf::from(output.as_deref().unwrap_or("./dataset"));
+            let format = match ImageFormat::from_extension(format) {
+                Some(f) => f,
+                None => bail!("Unknown image format {}", format),
+            };
+            let options = ExportOptions {
+                level: *level,
+                size: *size,
+                stride: *stride,
+                format,
+                labeled_only: *labeled_only,
+            };
+            for path in paths {
+                let count = export_tiles(&PathBuf::from(path), &out_dir, &options)?;
+                log::info!("Exported {} patches from {}", count, path);
+            }
+        }
+        Commands::ExportDzi(args) => {
+            let ExportDziArgs {
+                path_str,
+                out_path,
+                fill_missing,
+            } = args;
+            let path = PathBuf::from(path_str);
+            let dzi_path = export_dzi(&path, &PathBuf::from(out_path), *fill_missing)?;
+            log::info!("Wrote {}", dzi_path.display());
+        }
+        Commands::ExportTiff(args) => {
+            let ExportTiffArgs {
+                path_str,
+                out_path,
+                bigtiff,
+            } = args;
+            let path = PathBuf::from(path_str);
+            let out_path = match out_path {
+                Some(s) => PathBuf::from(s),
+                None => path.with_extension("ome.tiff"),
+            };
+            export_tiff(&path, &out_path, *bigtiff)?;
+            log::info!("Wrote {}", out_path.display());
+        }
+        Commands::Serve(args) => {
+            let ServeArgs { dir, host, port } = args;
+            serve(&PathBuf::from(dir), &format!("{}:{}", host, port))?;
+        }
+        Commands::Synth(args) => {
+            let SynthArgs {
+                out_path,
+                width,
+                height,
+                seed,
+                config,
+                force,
+            } = args;
+            let output = PathBuf::from(out_path);
+            if output.exists() {
+                if !force {
+                    bail!("{} already exists", output.display());
+                }
+                log::warn!("Overwriting {}", output.display());
+                std::fs::remove_file(&output)?;
+            }
+            let slide = SynthSlide::random(*width, *height, *seed);
+            log::debug!("Generating {}x{} slide: {:?}", width, height, slide.shapes);
+            if is_sqlite(&output) {
+                let config = match config {
+                    Some(s) => Config::from(PathBuf::from(s))?,
+                    None => Config::default(),
+                };
+                convert_source(&slide.to_source(), output, &config)?;
+            } else {
+                slide.render().save(&output)?;
+            }
+        }
 
         Commands::Thumbnail(args) => {
             let ThumbnailArgs {
                 path,
                 out_path,
                 size,
+                associated,
             } = args;
 
             let slide_path = PathBuf::from(path);
@@ -227,26 +610,162 @@ fn main() -> Result<()> {
             if output.is_dir() {
                 output = output.join("thumbnail.jpg");
             }
-            let ext = match slide_path.extension() {
-                Some(oss) => oss.to_string_lossy().to_string(),
-                None => "".to_owned(),
+            let format = match ImageFormat::from_path(&output) {
+                Ok(f @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)) => f,
+                _ => bail!("Unsupported thumbnail format {}", output.display()),
             };
-            if ext == "sqlite" {
-                let slide = Database::open(&slide_path)?;
-                let patch = slide.thumbnail(*size)?;
-                let image = patch.image()?;
-                let mut out_file = File::create(output)?;
-                image.write_to(&mut out_file, ImageFormat::Jpeg)?;
-                dbg!(size);
+            let (image, associated_images) = if is_sqlite(&slide_path) {
+                sqlite_thumbnail(&slide_path, *size, *associated)?
             } else {
-                bail!("Only sqlite thumbnails are supported.")
+                slide_thumbnail(&slide_path, *size, *associated)?
+            };
+            save_image(&image, &output, format)?;
+            for (name, image) in associated_images {
+                let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+                let file_name = format!("{}_{}", stem, name);
+                let mut path = output.with_file_name(file_name);
+                if let Some(ext) = output.extension() {
+                    path.set_extension(ext);
+                }
+                save_image(&image, &path, format)?;
             }
         }
         Commands::Metadata(args) => {
-            let MetadataArgs { path } = args;
-            dbg!(path);
+            let MetadataArgs { path, json } = args;
+            let slide_path = PathBuf::from(path);
+            if !slide_path.is_file() {
+                bail!("Path {} does not exist", slide_path.display());
+            }
+            let metadata = if is_sqlite(&slide_path) {
+                sqlite_metadata(&slide_path)?
+            } else {
+                slide_metadata(&slide_path)?
+            };
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&metadata)?);
+            } else {
+                print_metadata(&metadata, 0);
+            }
+        }
+
+        Commands::Region(args) => {
+            let RegionArgs {
+                path_str,
+                out_path,
+                x,
+                y,
+                width,
+                height,
+                level,
+                mpp,
+            } = args;
+            let db = Database::open(&PathBuf::from(path_str))?;
+            let output = PathBuf::from(out_path);
+            let format = ImageFormat::from_path(&output)?;
+            let patch = match mpp {
+                Some(mpp) => db.read_region_microns((*x, *y), (*width, *height), *mpp)?,
+                None => {
+                    let level = level.unwrap_or(db.levels() - 1);
+                    let origin = (to_pixels(*x)?, to_pixels(*y)?);
+                    let size = (to_pixels(*width)?, to_pixels(*height)?);
+                    db.read_region_level(origin, size, level)?
+                }
+            };
+            if patch.is_empty() {
+                bail!("No tiles in the region at level {}", patch.level());
+            }
+            save_image(patch.image()?, &output, format)?;
         }
 
+        Commands::Label(args) => match &args.command {
+            LabelCommands::Add(args) => {
+                let LabelAddArgs {
+                    path_str,
+                    x,
+                    y,
+                    label,
+                    level,
+                    source,
+                } = args;
+                let db = Database::open_readwrite(&PathBuf::from(path_str))?;
+                let level = level.unwrap_or(db.levels() - 1);
+                let label = TileLabel::from(label)?;
+                db.add_label((*x, *y), level, label, source)?;
+            }
+            LabelCommands::List(args) => {
+                let LabelListArgs {
+                    path_str,
+                    pos,
+                    level,
+                } = args;
+                let db = Database::open(&PathBuf::from(path_str))?;
+                let level = level.unwrap_or(db.levels() - 1);
+                let labels = match pos {
+                    Some(p) => db.list_labels((p[0], p[1]), level)?,
+                    None => db.list_level_labels(level)?,
+                };
+                for l in labels {
+                    let undo = if l.undo { " (undone)" } else { "" };
+                    println!(
+                        "{} {} {} {} {} {}{}",
+                        l.pos.0, l.pos.1, l.level, l.label, l.source, l.unix_time, undo
+                    );
+                }
+            }
+            LabelCommands::Effective(args) => {
+                let LabelEffectiveArgs { path_str, level } = args;
+                let db = Database::open(&PathBuf::from(path_str))?;
+                let level = level.unwrap_or(db.levels() - 1);
+                let mut labels: Vec<_> = db.effective_labels(level)?.into_iter().collect();
+                labels.sort_by_key(|(pos, _)| (pos.1, pos.0));
+                for ((x, y), label) in labels {
+                    println!("{} {} {}", x, y, label);
+                }
+            }
+            LabelCommands::Undo(args) => {
+                let LabelUndoArgs {
+                    path_str,
+                    x,
+                    y,
+                    level,
+                } = args;
+                let db = Database::open_readwrite(&PathBuf::from(path_str))?;
+                let level = level.unwrap_or(db.levels() - 1);
+                if !db.undo_label((*x, *y), level)? {
+                    log::warn!("No label to undo for tile {} {}", x, y);
+                }
+            }
+        },
+
+        Commands::Distances(args) => match &args.command {
+            DistancesCommands::Compute(args) => {
+                let DistancesComputeArgs {
+                    path_str,
+                    embeddings,
+                    k,
+                    metric,
+                } = args;
+                let db = Database::open_readwrite(&PathBuf::from(path_str))?;
+                let embeddings = Embeddings::from(&PathBuf::from(embeddings))?;
+                let metric = Metric::from_str(metric)?;
+                let count = db.write_neighbours(&embeddings, *k, metric)?;
+                log::info!("Stored {} distances", count);
+            }
+            DistancesCommands::Neighbours(args) => {
+                let DistancesNeighboursArgs {
+                    path_str,
+                    x,
+                    y,
+                    level,
+                } = args;
+                let db = Database::open(&PathBuf::from(path_str))?;
+                let level = level.unwrap_or(db.levels() - 1);
+                for n in db.neighbours((*x, *y), level)? {
+                    println!("{} {} {} {}", n.pos.0, n.pos.1, n.level, n.distance);
+                }
+            }
+        },
+
         Commands::Types(args) => {
             let TypesArgs { out_path } = args;
 
@@ -268,3 +787,182 @@ fn main() -> Result<()> {
     };
     Ok(())
 }
+
+fn is_sqlite(path: &Path) -> bool {
+    match path.extension() {
+        Some(oss) => oss.to_string_lossy() == "sqlite",
+        None => false,
+    }
+}
+
+/// Region arguments are whole pixels unless they are given in micrometres.
+fn to_pixels(value: f64) -> Result<u64> {
+    if value < 0.0 || value.fract() != 0.0 {
+        bail!(
+            "{} is not a pixel position, use --mpp for micrometres",
+            value
+        );
+    }
+    Ok(value as u64)
+}
+
+fn to_mpp(ppm: u64) -> f64 {
+    if ppm == 0 {
+        return 0.0;
+    }
+    1_000_000.0 / ppm as f64
+}
+
+/// Metadata keys describing the slide, all other plain keys are config values.
+const SLIDE_KEYS: [&str; 8] = [
+    "tile_size",
+    "levels",
+    "width",
+    "height",
+    "x_ppm",
+    "y_ppm",
+    "crop_x",
+    "crop_y",
+];
+
+fn sqlite_metadata(path: &Path) -> Result<Value> {
+    let db = Database::open(&path.to_path_buf())?;
+    let data = &db.data;
+
+    let mut tiles = Map::new();
+    let mut label_counts: BTreeMap<String, u64> = BTreeMap::new();
+    for (level, count) in db.tile_counts()? {
+        tiles.insert(level.to_string(), json!(count));
+        for label in db.effective_labels(level)?.values() {
+            *label_counts.entry(label.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    let metadata: BTreeMap<String, String> = db.read_metadata()?.into_iter().collect();
+    let config: Map<String, Value> = metadata
+        .into_iter()
+        .filter(|(k, _)| !SLIDE_KEYS.contains(&k.as_str()) && !k.starts_with(PROPERTY_PREFIX))
+        .map(|(k, v)| (k, json!(v)))
+        .collect();
+    let properties: BTreeMap<String, String> = db.read_properties()?.into_iter().collect();
+
+    Ok(json!({
+        "path": path.display().to_string(),
+        "width": data.width,
+        "height": data.height,
+        "tile_size": data.tile_size,
+        "levels": data.levels,
+        "mpp": [to_mpp(data.x_ppm), to_mpp(data.y_ppm)],
+        "tiles": tiles,
+        "labels": label_counts,
+        "config": config,
+        "properties": properties,
+        "associated_images": db.list_associated_images()?,
+    }))
+}
+
+fn slide_metadata(path: &Path) -> Result<Value> {
+    let slide = open_slide(&path.to_path_buf())?;
+    let mpp = match slide.resolution() {
+        Ok((x_ppm, y_ppm)) => json!([to_mpp(x_ppm), to_mpp(y_ppm)]),
+        Err(_) => Value::Null,
+    };
+    let mut levels = Vec::new();
+    for level in 0..slide.level_count()? {
+        let (w, h) = slide.level_dimensions(level)?;
+        let downsample = slide.level_downsample(level)?;
+        levels.push(json!(format!("{}x{} (downsample {})", w, h, downsample)));
+    }
+    let properties: BTreeMap<String, String> = slide.properties()?.into_iter().collect();
+    let (width, height) = slide.size();
+
+    Ok(json!({
+        "path": path.display().to_string(),
+        "vendor": slide.vendor(),
+        "width": width,
+        "height": height,
+        "mpp": mpp,
+        "levels": levels,
+        "properties": properties,
+        "associated_images": slide.associated_image_names()?,
+    }))
+}
+
+const ASSOCIATED_IMAGES: [&str; 2] = ["label", "macro"];
+
+fn save_image(image: &RgbImage, path: &Path, format: ImageFormat) -> Result<()> {
+    log::debug!("Writing {}", path.display());
+    let mut out_file = File::create(path)?;
+    image.write_to(&mut out_file, format)?;
+    Ok(())
+}
+
+fn sqlite_thumbnail(
+    path: &Path,
+    size: u64,
+    associated: bool,
+) -> Result<(RgbImage, Vec<(String, RgbImage)>)> {
+    let slide = Database::open(&path.to_path_buf())?;
+    let patch = slide.thumbnail(size)?;
+    let image = patch.image()?.clone();
+    let mut associated_images = Vec::new();
+    if associated {
+        for name in ASSOCIATED_IMAGES {
+            if let Some(image) = slide.read_associated_image(name)? {
+                associated_images.push((name.to_owned(), image));
+            }
+        }
+    }
+    Ok((image, associated_images))
+}
+
+fn slide_thumbnail(
+    path: &Path,
+    size: u64,
+    associated: bool,
+) -> Result<(RgbImage, Vec<(String, RgbImage)>)> {
+    let slide = open_slide(&path.to_path_buf())?;
+    let image = slide.thumbnail(size)?;
+    let mut associated_images = Vec::new();
+    if associated {
+        let names = slide.associated_image_names()?;
+        for name in ASSOCIATED_IMAGES {
+            if names.iter().any(|n| n == name) {
+                let image = slide.read_associated_image(name)?;
+                associated_images.push((name.to_owned(), image));
+            }
+        }
+    }
+    Ok((image, associated_images))
+}
+
+fn print_metadata(value: &Value, indent: usize) {
+    let pad = " ".repeat(indent);
+    let Value::Object(map) = value else {
+        println!("{}{}", pad, value);
+        return;
+    };
+    for (key, value) in map {
+        match value {
+            Value::Object(m) if m.is_empty() => {}
+            Value::Object(_) => {
+                println!("{}{}:", pad, key);
+                print_metadata(value, indent + 2);
+            }
+            Value::Array(items) if items.is_empty() => {}
+            Value::Array(items) => {
+                let items: Vec<String> = items.iter().map(format_value).collect();
+                println!("{}{}: {}", pad, key, items.join(", "));
+            }
+            _ => println!("{}{}: {}", pad, key, format_value(value)),
+        }
+    }
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => "unknown".to_owned(),
+        v => v.to_string(),
+    }
+}
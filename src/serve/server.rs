@@ -0,0 +1,440 @@
+use anyhow::{anyhow, Result};
+use image::{codecs::jpeg::JpegEncoder, DynamicImage};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use tiny_http::{Header, Response};
+
+use crate::export::DeepZoom;
+use crate::{Database, TileLabel};
+
+const DEFAULT_THUMBNAIL_SIZE: u64 = 512;
+
+/// Status, content type and body of a response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    fn image(content_type: &'static str, body: Vec<u8>) -> Reply {
+        Reply {
+            status: 200,
+            content_type,
+            body,
+        }
+    }
+    fn json(value: Value) -> Reply {
+        Reply {
+            status: 200,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+        }
+    }
+    fn text(status: u16, text: &str) -> Reply {
+        Reply {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: text.as_bytes().to_vec(),
+        }
+    }
+    fn not_found() -> Reply {
+        Reply::text(404, "Not found")
+    }
+}
+
+#[derive(Deserialize)]
+struct LabelRequest {
+    label: String,
+    source: Option<String>,
+}
+
+/// Decodes %XX escapes of a url path segment.
+fn decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn parse_pos(x: &str, y: &str) -> Option<(u64, u64)> {
+    Some((x.parse().ok()?, y.parse().ok()?))
+}
+
+/// Tile file name without its extension, tiles are served in the codec of the slide.
+fn stem(file: &str) -> Option<&str> {
+    file.rsplit_once('.').map(|(stem, _)| stem)
+}
+
+/// Serves the `.sqlite` slides of a directory, slides are addressed by their file stem.
+pub struct Server {
+    dir: PathBuf,
+    slides: HashMap<String, Database>,
+}
+
+impl Server {
+    pub fn new(dir: &Path) -> Result<Server> {
+        if !dir.is_dir() {
+            return Err(anyhow!("{} is not a directory", dir.display()));
+        }
+        Ok(Server {
+            dir: dir.to_path_buf(),
+            slides: HashMap::new(),
+        })
+    }
+
+    /// Names of the slides in the directory.
+    pub fn list_slides(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|e| e == "sqlite") {
+                if let Some(stem) = path.file_stem() {
+                    names.push(stem.to_string_lossy().to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn slide_path(&self, name: &str) -> Option<PathBuf> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return None;
+        }
+        let path = self.dir.join(format!("{}.sqlite", name));
+        path.is_file().then_some(path)
+    }
+
+    /// The read only database of a slide, kept open between requests.
+    fn slide(&mut self, name: &str) -> Result<Option<&Database>> {
+        if !self.slides.contains_key(name) {
+            let Some(path) = self.slide_path(name) else {
+                return Ok(None);
+            };
+            self.slides.insert(name.to_owned(), Database::open(&path)?);
+        }
+        Ok(self.slides.get(name))
+    }
+
+    pub fn handle(&mut self, method: &str, url: &str, body: &[u8]) -> Reply {
+        match self.route(method, url, body) {
+            Ok(reply) => reply,
+            Err(e) => {
+                log::warn!("{} {}: {}", method, url, e);
+                Reply::text(500, &e.to_string())
+            }
+        }
+    }
+
+    fn route(&mut self, method: &str, url: &str, body: &[u8]) -> Result<Reply> {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let segments: Vec<String> = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(decode)
+            .collect();
+        let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+
+        match (method, segments.as_slice()) {
+            ("GET", ["slides"]) => Ok(Reply::json(json!(self.list_slides()?))),
+            ("GET", ["slides", dzi]) if dzi.ends_with(".dzi") => {
+                let Some(db) = self.slide(dzi.trim_end_matches(".dzi"))? else {
+                    return Ok(Reply::not_found());
+                };
+                let xml = DeepZoom::new(db)?.xml();
+                Ok(Reply {
+                    status: 200,
+                    content_type: "application/xml",
+                    body: xml.into_bytes(),
+                })
+            }
+            ("GET", ["slides", files, level, tile]) if files.ends_with("_files") => {
+                let name = files.trim_end_matches("_files");
+                let pos = stem(tile)
+                    .and_then(|t| t.split_once('_'))
+                    .and_then(|(x, y)| parse_pos(x, y));
+                let (Some(pos), Ok(level)) = (pos, level.parse::<u64>()) else {
+                    return Ok(Reply::not_found());
+                };
+                self.dzi_tile(name, level, pos)
+            }
+            ("GET", ["slides", name, "metadata"]) => self.metadata(name),
+            ("GET", ["slides", name, "thumbnail"]) => {
+                let size = query
+                    .split('&')
+                    .find_map(|p| p.strip_prefix("size="))
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(DEFAULT_THUMBNAIL_SIZE);
+                self.thumbnail(name, size)
+            }
+            ("GET", ["slides", name, "tiles", level, x, y]) => {
+                let pos = stem(y).and_then(|y| parse_pos(x, y));
+                let (Some(pos), Ok(level)) = (pos, level.parse::<u64>()) else {
+                    return Ok(Reply::not_found());
+                };
+                let Some(db) = self.slide(name)? else {
+                    return Ok(Reply::not_found());
+                };
+                match db.read_data(pos, level)? {
+                    Some(data) => Ok(Reply::image(db.codec().mime_type(), data)),
+                    None => Ok(Reply::not_found()),
+                }
+            }
+            ("GET", ["slides", name, "labels", level]) => {
+                let Ok(level) = level.parse::<u64>() else {
+                    return Ok(Reply::not_found());
+                };
+                self.labels(name, level)
+            }
+            ("POST", ["slides", name, "labels", level, x, y]) => {
+                let (Some(pos), Ok(level)) = (parse_pos(x, y), level.parse::<u64>()) else {
+                    return Ok(Reply::not_found());
+                };
+                let request: LabelRequest = match serde_json::from_slice(body) {
+                    Ok(r) => r,
+                    Err(e) => return Ok(Reply::text(400, &e.to_string())),
+                };
+                let label = match TileLabel::from(&request.label) {
+                    Ok(l) => l,
+                    Err(e) => return Ok(Reply::text(400, &e.to_string())),
+                };
+                let source = request.source.unwrap_or_else(|| "pamly serve".to_owned());
+                let Some(path) = self.slide_path(name) else {
+                    return Ok(Reply::not_found());
+                };
+                let db = Database::open_readwrite(&path)?;
+                if db.tile_id(pos, level)?.is_none() {
+                    return Ok(Reply::not_found());
+                }
+                db.add_label(pos, level, label, &source)?;
+                Ok(Reply::json(json!({ "label": label.to_string() })))
+            }
+            ("POST", ["slides", name, "labels", level, x, y, "undo"]) => {
+                let (Some(pos), Ok(level)) = (parse_pos(x, y), level.parse::<u64>()) else {
+                    return Ok(Reply::not_found());
+                };
+                let Some(path) = self.slide_path(name) else {
+                    return Ok(Reply::not_found());
+                };
+                let db = Database::open_readwrite(&path)?;
+                if db.tile_id(pos, level)?.is_none() {
+                    return Ok(Reply::not_found());
+                }
+                let undone = db.undo_label(pos, level)?;
+                let label = db.effective_label(pos, level)?.map(|l| l.to_string());
+                Ok(Reply::json(json!({ "undone": undone, "label": label })))
+            }
+            ("GET" | "POST", _) => Ok(Reply::not_found()),
+            _ => Ok(Reply::text(405, "Method not allowed")),
+        }
+    }
+
+    fn dzi_tile(&mut self, name: &str, level: u64, pos: (u64, u64)) -> Result<Reply> {
+        let Some(db) = self.slide(name)? else {
+            return Ok(Reply::not_found());
+        };
+        let dzi = DeepZoom::new(db)?;
+        if level >= dzi.levels() {
+            return Ok(Reply::not_found());
+        }
+        let (columns, rows) = dzi.tile_count(level)?;
+        if pos.0 >= columns || pos.1 >= rows {
+            return Ok(Reply::not_found());
+        }
+        // Viewers expect every tile of the pyramid, background is served white.
+        let data = match dzi.tile(level, pos)? {
+            Some(data) => data,
+            None => dzi.blank_tile(level, pos)?,
+        };
+        Ok(Reply::image(db.codec().mime_type(), data))
+    }
+
+    fn metadata(&mut self, name: &str) -> Result<Reply> {
+        let Some(db) = self.slide(name)? else {
+            return Ok(Reply::not_found());
+        };
+        let data = &db.data;
+        let mpp = db.mpp().ok();
+        Ok(Reply::json(json!({
+            "name": name,
+            "width": data.width,
+            "height": data.height,
+            "tile_size": data.tile_size,
+            "levels": data.levels,
+            "mpp": mpp,
+            "metadata": db.read_metadata()?,
+            "properties": db.read_properties()?,
+        })))
+    }
+
+    fn thumbnail(&mut self, name: &str, size: u64) -> Result<Reply> {
+        let Some(db) = self.slide(name)? else {
+            return Ok(Reply::not_found());
+        };
+        let patch = db.thumbnail(size)?;
+        if patch.is_empty() {
+            return Ok(Reply::not_found());
+        }
+        let mut bytes = Cursor::new(Vec::new());
+        let encoder = JpegEncoder::new_with_quality(&mut bytes, 90);
+        DynamicImage::ImageRgb8(patch.image()?.clone()).write_with_encoder(encoder)?;
+        Ok(Reply::image("image/jpeg", bytes.into_inner()))
+    }
+
+    fn labels(&mut self, name: &str, level: u64) -> Result<Reply> {
+        let Some(db) = self.slide(name)? else {
+            return Ok(Reply::not_found());
+        };
+        let mut labels: Vec<_> = db.effective_label_entries(level)?.into_values().collect();
+        labels.sort_by_key(|l| (l.pos.1, l.pos.0));
+        let labels: Vec<Value> = labels
+            .iter()
+            .map(|l| {
+                json!({
+                    "x": l.pos.0,
+                    "y": l.pos.1,
+                    "label": l.label.to_string(),
+                    "source": l.source,
+                    "unix_time": l.unix_time,
+                })
+            })
+            .collect();
+        Ok(Reply::json(json!(labels)))
+    }
+}
+
+/// Serves the slides of `dir` over HTTP until the process is stopped.
+pub fn serve(dir: &Path, address: &str) -> Result<()> {
+    let mut app = Server::new(dir)?;
+    let server = tiny_http::Server::http(address).map_err(|e| anyhow!("{}: {}", address, e))?;
+    log::info!(
+        "Serving {} slides from {} on http://{}",
+        app.list_slides()?.len(),
+        dir.display(),
+        address
+    );
+    for mut request in server.incoming_requests() {
+        let mut body = Vec::new();
+        if let Err(e) = request.as_reader().read_to_end(&mut body) {
+            log::warn!("Could not read request body: {}", e);
+            continue;
+        }
+        let reply = app.handle(request.method().as_str(), request.url(), &body);
+        log::debug!("{} {} {}", request.method(), request.url(), reply.status);
+        let header = Header::from_bytes("Content-Type", reply.content_type)
+            .map_err(|_| anyhow!("Invalid content type {}", reply.content_type))?;
+        let response = Response::from_data(reply.body)
+            .with_status_code(reply.status)
+            .with_header(header);
+        if let Err(e) = request.respond(response) {
+            log::warn!("Could not send response: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, Server};
+    use crate::database::testing::test_db;
+    use crate::Tile;
+    use anyhow::Result;
+    use image::{Rgb, RgbImage};
+    use serde_json::Value;
+
+    #[test]
+    fn serve_slides() -> Result<()> {
+        let (db, path) = test_db("serve")?;
+        let mut root = Tile::new((0, 0), 0, 4);
+        root.set_image(RgbImage::from_pixel(4, 4, Rgb([0, 0, 0])))?;
+        db.write(&root)?;
+        drop(db);
+        let dir = std::env::temp_dir().join(format!("pamly_serve_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::rename(&path, dir.join("my slide.sqlite"))?;
+        let mut server = Server::new(&dir)?;
+
+        let reply = server.handle("GET", "/slides", b"");
+        assert_eq!(reply.body, b"[\"my slide\"]");
+
+        let reply = server.handle("GET", "/slides/my%20slide/tiles/1/1/1.jpg", b"");
+        assert_eq!(reply.content_type, "image/jpeg");
+        let db = server.slide("my slide")?.unwrap();
+        assert_eq!(Some(reply.body), db.read_data((1, 1), 1)?);
+        let reply = server.handle("GET", "/slides/my%20slide/tiles/1/0/1.jpg", b"");
+        assert_eq!(reply.status, 404);
+        assert_eq!(
+            server.handle("GET", "/slides/other/metadata", b"").status,
+            404
+        );
+
+        let reply = server.handle("GET", "/slides/my%20slide/metadata", b"");
+        let metadata: Value = serde_json::from_slice(&reply.body)?;
+        assert_eq!(metadata["width"], 8);
+        assert_eq!(metadata["levels"], 2);
+
+        let reply = server.handle("GET", "/slides/my%20slide.dzi", b"");
+        assert!(String::from_utf8(reply.body)?.contains("TileSize=\"4\""));
+        let reply = server.handle("GET", "/slides/my%20slide_files/3/0_1.jpg", b"");
+        assert_eq!((reply.status, reply.content_type), (200, "image/jpeg"));
+        for url in [
+            "/slides/my%20slide_files/99/0_0.jpg",
+            "/slides/my%20slide_files/3/2_0.jpg",
+        ] {
+            assert_eq!(server.handle("GET", url, b"").status, 404);
+        }
+        let reply = server.handle("GET", "/slides/my%20slide/thumbnail?size=4", b"");
+        let thumbnail = image::load_from_memory(&reply.body)?;
+        assert_eq!((thumbnail.width(), thumbnail.height()), (4, 4));
+
+        let url = "/slides/my%20slide/labels/1/1/0";
+        let reply = server.handle("POST", url, br#"{"label": "tumor", "source": "alice"}"#);
+        assert_eq!(reply.status, 200);
+        assert_eq!(server.handle("POST", url, b"{}").status, 400);
+        let reply = server.handle("POST", url, br#"{"label": "nothing"}"#);
+        assert_eq!(reply.status, 400);
+        let reply = server.handle(
+            "POST",
+            "/slides/my%20slide/labels/1/0/1",
+            br#"{"label": "Tumor"}"#,
+        );
+        assert_eq!(reply.status, 404);
+
+        let reply = server.handle("GET", "/slides/my%20slide/labels/1", b"");
+        let labels: Value = serde_json::from_slice(&reply.body)?;
+        assert_eq!(labels[0]["x"], 1);
+        assert_eq!(labels[0]["label"], "Tumor");
+        assert_eq!(labels[0]["source"], "alice");
+
+        let reply = server.handle("POST", &format!("{}/undo", url), b"");
+        let undo: Value = serde_json::from_slice(&reply.body)?;
+        assert_eq!(undo["undone"], true);
+        assert_eq!(undo["label"], Value::Null);
+        assert_eq!(server.handle("DELETE", url, b"").status, 405);
+
+        assert_eq!(decode("a%20b%2"), "a b%2");
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,283 @@
+use anyhow::{bail, Result};
+use std::io::{BufRead, BufReader};
+use std::{collections::BinaryHeap, fs::File, path::PathBuf};
+use strum::{Display, EnumString};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum Metric {
+    Cosine,
+    L2,
+}
+
+impl Metric {
+    pub fn distance(&self, a: &[f32], b: &[f32]) -> f64 {
+        match self {
+            Metric::L2 => a
+                .iter()
+                .zip(b)
+                .map(|(x, y)| (*x as f64 - *y as f64).powi(2))
+                .sum::<f64>()
+                .sqrt(),
+            Metric::Cosine => {
+                let mut dot = 0.0;
+                let mut norm_a = 0.0;
+                let mut norm_b = 0.0;
+                for (x, y) in a.iter().zip(b) {
+                    let (x, y) = (*x as f64, *y as f64);
+                    dot += x * y;
+                    norm_a += x * x;
+                    norm_b += y * y;
+                }
+                if norm_a == 0.0 || norm_b == 0.0 {
+                    return 1.0;
+                }
+                1.0 - dot / (norm_a.sqrt() * norm_b.sqrt())
+            }
+        }
+    }
+}
+
+/// Feature vectors of tiles, keyed by tile id.
+#[derive(Debug, Clone)]
+pub struct Embeddings {
+    pub ids: Vec<u64>,
+    pub vectors: Vec<Vec<f32>>,
+}
+
+#[derive(PartialEq)]
+struct Candidate(f64, usize);
+impl Eq for Candidate {}
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+fn parse_npy_header(header: &str) -> Result<(String, bool, Vec<usize>)> {
+    let value = |key: &str| -> Result<&str> {
+        let pattern = format!("'{}':", key);
+        match header.find(&pattern) {
+            Some(i) => Ok(header[i + pattern.len()..].trim_start()),
+            None => bail!("npy header is missing {}", key),
+        }
+    };
+    let descr = value("descr")?;
+    let descr = match descr.split('\'').nth(1) {
+        Some(d) => d.to_owned(),
+        None => bail!("Invalid npy descr"),
+    };
+    let fortran_order = value("fortran_order")?.starts_with("True");
+    let shape = value("shape")?;
+    let end = match shape.find(')') {
+        Some(e) => e,
+        None => bail!("Invalid npy shape"),
+    };
+    let shape = shape[1..end]
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<usize>())
+        .collect::<Result<Vec<usize>, _>>()?;
+    Ok((descr, fortran_order, shape))
+}
+
+impl Embeddings {
+    /// Reads embeddings from a .npy or .csv file.
+    /// The first column is the tile id, all other columns are features.
+    pub fn from(path: &PathBuf) -> Result<Embeddings> {
+        let ext = match path.extension() {
+            Some(oss) => oss.to_string_lossy().to_lowercase(),
+            None => "".to_owned(),
+        };
+        match ext.as_str() {
+            "npy" => Embeddings::from_npy(&std::fs::read(path)?),
+            "csv" => Embeddings::from_csv(BufReader::new(File::open(path)?)),
+            _ => bail!("Unsupported embeddings file {}", path.display()),
+        }
+    }
+
+    /// Parses a 2d float32 or float64 npy array of shape (tiles, 1 + features).
+    pub fn from_npy(data: &[u8]) -> Result<Embeddings> {
+        if data.len() < 10 || &data[0..6] != b"\x93NUMPY" {
+            bail!("Not a npy file");
+        }
+        let (header_len, offset) = match data[6] {
+            1 => (u16::from_le_bytes([data[8], data[9]]) as usize, 10),
+            2 | 3 => {
+                if data.len() < 12 {
+                    bail!("Invalid npy header");
+                }
+                let len = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+                (len as usize, 12)
+            }
+            v => bail!("Unsupported npy version {}", v),
+        };
+        if data.len() < offset + header_len {
+            bail!("Invalid npy header");
+        }
+        let header = String::from_utf8_lossy(&data[offset..offset + header_len]);
+        let (descr, fortran_order, shape) = parse_npy_header(&header)?;
+        if shape.len() != 2 || shape[1] < 2 {
+            bail!(
+                "Expected an array of shape (tiles, 1 + features), got {:?}",
+                shape
+            );
+        }
+        let (rows, cols) = (shape[0], shape[1]);
+        let body = &data[offset + header_len..];
+        let values: Vec<f64> = match descr.as_str() {
+            "<f4" => body
+                .chunks_exact(4)
+                .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]) as f64)
+                .collect(),
+            "<f8" => body
+                .chunks_exact(8)
+                .map(|c| f64::from_le_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]))
+                .collect(),
+            d => bail!("Unsupported npy dtype {}, use float32 or float64", d),
+        };
+        if values.len() != rows * cols {
+            bail!("npy data does not match shape {:?}", shape);
+        }
+        let at = |r: usize, c: usize| {
+            if fortran_order {
+                values[c * rows + r]
+            } else {
+                values[r * cols + c]
+            }
+        };
+        let mut ids = Vec::with_capacity(rows);
+        let mut vectors = Vec::with_capacity(rows);
+        for r in 0..rows {
+            ids.push(at(r, 0) as u64);
+            vectors.push((1..cols).map(|c| at(r, c) as f32).collect());
+        }
+        Ok(Embeddings { ids, vectors })
+    }
+
+    /// Parses comma separated rows of `id,feature,feature,...`, a header line is skipped.
+    pub fn from_csv<R: BufRead>(reader: R) -> Result<Embeddings> {
+        let mut ids = Vec::new();
+        let mut vectors = Vec::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut fields = line.split(',').map(|s| s.trim());
+            let id = match fields.next().map(|s| s.parse::<u64>()) {
+                Some(Ok(id)) => id,
+                _ if i == 0 => continue,
+                _ => bail!("Invalid tile id in line {}", i + 1),
+            };
+            let vector = fields
+                .map(|s| s.parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()?;
+            ids.push(id);
+            vectors.push(vector);
+        }
+        let embeddings = Embeddings { ids, vectors };
+        embeddings.check()?;
+        Ok(embeddings)
+    }
+
+    fn check(&self) -> Result<()> {
+        let dim = self.dim();
+        if dim == 0 {
+            bail!("Embeddings have no features");
+        }
+        if self.vectors.iter().any(|v| v.len() != dim) {
+            bail!("Embeddings have inconsistent lengths");
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+    pub fn dim(&self) -> usize {
+        self.vectors.first().map(|v| v.len()).unwrap_or(0)
+    }
+
+    /// The k nearest neighbours of every embedding as (tile, neighbour, distance).
+    pub fn nearest_neighbours(&self, k: usize, metric: Metric) -> Vec<(u64, u64, f64)> {
+        let mut result = Vec::with_capacity(self.len() * k);
+        for (i, a) in self.vectors.iter().enumerate() {
+            let mut heap: BinaryHeap<Candidate> = BinaryHeap::with_capacity(k + 1);
+            for (j, b) in self.vectors.iter().enumerate() {
+                if i == j || self.ids[i] == self.ids[j] {
+                    continue;
+                }
+                heap.push(Candidate(metric.distance(a, b), j));
+                if heap.len() > k {
+                    heap.pop();
+                }
+            }
+            for Candidate(distance, j) in heap.into_sorted_vec() {
+                result.push((self.ids[i], self.ids[j], distance));
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Embeddings, Metric, Result};
+
+    fn npy(values: &[f32], shape: (usize, usize)) -> Vec<u8> {
+        let header = format!(
+            "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}\n",
+            shape.0, shape.1
+        );
+        let mut data = b"\x93NUMPY\x01\x00".to_vec();
+        data.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        data.extend_from_slice(header.as_bytes());
+        for v in values {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn parse_npy() -> Result<()> {
+        let data = npy(&[7.0, 1.0, 2.0, 9.0, 3.0, 4.0], (2, 3));
+        let e = Embeddings::from_npy(&data)?;
+        assert_eq!(e.ids, vec![7, 9]);
+        assert_eq!(e.vectors[1], vec![3.0, 4.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_csv() -> Result<()> {
+        let csv = "id,a,b\n1,0.5,1\n2,1,0\n";
+        let e = Embeddings::from_csv(csv.as_bytes())?;
+        assert_eq!(e.ids, vec![1, 2]);
+        assert_eq!(e.dim(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn nearest() -> Result<()> {
+        let csv = "1,0,0\n2,1,0\n3,5,0\n4,0,10\n";
+        let e = Embeddings::from_csv(csv.as_bytes())?;
+        let nn = e.nearest_neighbours(2, Metric::L2);
+        assert_eq!(nn.len(), 8);
+        assert_eq!(nn[0], (1, 2, 1.0));
+        assert_eq!(nn[1], (1, 3, 5.0));
+
+        let nn = e.nearest_neighbours(1, Metric::Cosine);
+        assert_eq!(nn[1].0, 2);
+        assert_eq!(nn[1].1, 3);
+        Ok(())
+    }
+}
@@ -10,5 +10,14 @@ pub use tile_label::TileLabel;
 mod tile;
 pub use tile::Tile;
 
+mod tile_codec;
+pub use tile_codec::{TileCodec, DEFAULT_JPEG_QUALITY};
+
 mod patch;
 pub use patch::Patch;
+
+mod numpy;
+pub(crate) use numpy::to_numpy;
+
+mod embeddings;
+pub use embeddings::{Embeddings, Metric};
@@ -0,0 +1,14 @@
+use image::RgbImage;
+use pyo3::{prelude::*, types::PyBytes};
+
+/// Copy an rgb image into a numpy uint8 array of shape (h, w, 3).
+pub fn to_numpy<'py>(py: Python<'py>, image: &RgbImage) -> PyResult<Bound<'py, PyAny>> {
+    let numpy = py.import_bound("numpy")?;
+    let bytes = PyBytes::new_bound(py, image.as_raw());
+    let shape = (image.height() as usize, image.width() as usize, 3usize);
+    let array = numpy
+        .call_method1("frombuffer", (bytes, "uint8"))?
+        .call_method1("reshape", (shape,))?
+        .call_method0("copy")?;
+    Ok(array)
+}
@@ -1,9 +1,15 @@
 use anyhow::{bail, Result};
 use image::RgbImage;
+use pyo3::{pyclass, pymethods, Bound, PyAny, PyResult, Python};
 
+use super::to_numpy;
+
+#[pyclass]
 pub struct Patch {
     data: Option<RgbImage>,
+    #[pyo3(get)]
     level: u64,
+    #[pyo3(get)]
     pub coords: (u64, u64),
 }
 
@@ -44,3 +50,22 @@ impl Patch {
         self.coords
     }
 }
+
+#[pymethods]
+impl Patch {
+    #[pyo3(name = "image")]
+    fn py_image<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyAny>>> {
+        match &self.data {
+            Some(image) => Ok(Some(to_numpy(py, image)?)),
+            None => Ok(None),
+        }
+    }
+    #[pyo3(name = "is_empty")]
+    fn py_is_empty(&self) -> bool {
+        self.is_empty()
+    }
+    #[pyo3(name = "size")]
+    fn py_size(&self) -> Option<(u64, u64)> {
+        self.size().ok()
+    }
+}
@@ -1,9 +1,13 @@
 use anyhow::{bail, Result};
 use pyo3::{pyclass, pymethods, PyResult};
+use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, convert::TryFrom};
 use strum::{Display, EnumIter, EnumString, IntoEnumIterator};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter, Display, EnumString)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, EnumIter, Display, EnumString, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
 #[repr(u8)]
 #[pyclass(eq, eq_int)]
 #[rustfmt::skip]
@@ -70,6 +74,19 @@ impl TryFrom<u8> for Stain {
     }
 }
 
+impl TryFrom<String> for Stain {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Stain> {
+        Stain::from(&value)
+    }
+}
+
+impl From<Stain> for String {
+    fn from(stain: Stain) -> String {
+        stain.to_string()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{Result, Stain, TryFrom};
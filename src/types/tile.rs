@@ -1,11 +1,17 @@
 use anyhow::{bail, Result};
-use image::{codecs::jpeg::JpegEncoder, DynamicImage, RgbImage};
-use std::io::Cursor;
+use image::RgbImage;
+use pyo3::{pyclass, pymethods, Bound, PyAny, PyResult, Python};
 
+use super::{to_numpy, TileCodec};
+
+#[pyclass]
 pub struct Tile {
     data: Option<RgbImage>,
+    #[pyo3(get)]
     size: u64,
+    #[pyo3(get)]
     level: u64,
+    #[pyo3(get)]
     pub pos: (u64, u64),
 }
 
@@ -45,13 +51,12 @@ impl Tile {
             None => bail!("Tile is empty"),
         }
     }
+    /// Encodes the tile as JPEG with the default quality.
     pub fn data(&self) -> Result<Vec<u8>> {
-        let image = self.image()?;
-        let mut bytes = Cursor::new(Vec::new());
-        let img = DynamicImage::ImageRgb8(image.clone());
-        let encoder = JpegEncoder::new_with_quality(&mut bytes, 90);
-        img.write_with_encoder(encoder)?;
-        Ok(bytes.into_inner())
+        self.encode(TileCodec::default())
+    }
+    pub fn encode(&self, codec: TileCodec) -> Result<Vec<u8>> {
+        codec.encode(self.image()?)
     }
 
     pub fn size(&self) -> u64 {
@@ -74,3 +79,26 @@ impl Tile {
         (x * self.size, y * self.size)
     }
 }
+
+#[pymethods]
+impl Tile {
+    #[pyo3(name = "image")]
+    fn py_image<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyAny>>> {
+        match &self.data {
+            Some(image) => Ok(Some(to_numpy(py, image)?)),
+            None => Ok(None),
+        }
+    }
+    #[pyo3(name = "is_empty")]
+    fn py_is_empty(&self) -> bool {
+        self.is_empty()
+    }
+    #[pyo3(name = "index")]
+    fn py_index(&self) -> u64 {
+        self.index()
+    }
+    #[pyo3(name = "coords")]
+    fn py_coords(&self) -> (u64, u64) {
+        self.coords()
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::{bail, Result};
+use image::codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder};
+use image::{ExtendedColorType, ImageEncoder, ImageFormat, RgbImage};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+pub const DEFAULT_JPEG_QUALITY: u8 = 90;
+
+/// Encoding of the stored tiles, written as `jpeg:<quality>`, `png` or `webp`.
+/// PNG and WebP are lossless.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum TileCodec {
+    Jpeg { quality: u8 },
+    Png,
+    WebP,
+}
+
+impl Default for TileCodec {
+    fn default() -> TileCodec {
+        TileCodec::Jpeg {
+            quality: DEFAULT_JPEG_QUALITY,
+        }
+    }
+}
+
+impl TileCodec {
+    pub fn encode(&self, image: &RgbImage) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let (w, h) = image.dimensions();
+        let color = ExtendedColorType::Rgb8;
+        match self {
+            TileCodec::Jpeg { quality } => JpegEncoder::new_with_quality(&mut bytes, *quality)
+                .write_image(image, w, h, color)?,
+            TileCodec::Png => PngEncoder::new(&mut bytes).write_image(image, w, h, color)?,
+            TileCodec::WebP => {
+                WebPEncoder::new_lossless(&mut bytes).write_image(image, w, h, color)?
+            }
+        }
+        Ok(bytes)
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<RgbImage> {
+        let image = image::load_from_memory_with_format(data, self.format())?;
+        Ok(image.to_rgb8())
+    }
+
+    pub fn format(&self) -> ImageFormat {
+        match self {
+            TileCodec::Jpeg { .. } => ImageFormat::Jpeg,
+            TileCodec::Png => ImageFormat::Png,
+            TileCodec::WebP => ImageFormat::WebP,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TileCodec::Jpeg { .. } => "jpg",
+            TileCodec::Png => "png",
+            TileCodec::WebP => "webp",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        self.format().to_mime_type()
+    }
+}
+
+impl fmt::Display for TileCodec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TileCodec::Jpeg { quality } => write!(f, "jpeg:{}", quality),
+            TileCodec::Png => write!(f, "png"),
+            TileCodec::WebP => write!(f, "webp"),
+        }
+    }
+}
+
+impl FromStr for TileCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<TileCodec> {
+        let lower = s.trim().to_lowercase();
+        let (name, quality) = match lower.split_once(':') {
+            Some((name, quality)) => (name, Some(quality)),
+            None => (lower.as_str(), None),
+        };
+        match (name, quality) {
+            ("jpeg" | "jpg", None) => Ok(TileCodec::default()),
+            ("jpeg" | "jpg", Some(q)) => match q.parse::<u8>() {
+                Ok(quality @ 1..=100) => Ok(TileCodec::Jpeg { quality }),
+                _ => bail!("Invalid JPEG quality {}, expected 1 to 100", q),
+            },
+            ("png", None) => Ok(TileCodec::Png),
+            ("webp", None) => Ok(TileCodec::WebP),
+            _ => bail!(
+                "Unknown tile codec {}, expected jpeg[:quality], png or webp",
+                s
+            ),
+        }
+    }
+}
+
+impl TryFrom<String> for TileCodec {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<TileCodec> {
+        s.parse()
+    }
+}
+
+impl From<TileCodec> for String {
+    fn from(codec: TileCodec) -> String {
+        codec.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Result, TileCodec};
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn parse_codec() -> Result<()> {
+        assert_eq!("jpeg".parse::<TileCodec>()?, TileCodec::default());
+        assert_eq!(
+            "JPEG:75".parse::<TileCodec>()?,
+            TileCodec::Jpeg { quality: 75 }
+        );
+        assert_eq!("webp".parse::<TileCodec>()?, TileCodec::WebP);
+        assert!("jpeg:0".parse::<TileCodec>().is_err());
+        assert!("png:5".parse::<TileCodec>().is_err());
+        assert!("gif".parse::<TileCodec>().is_err());
+        for codec in [TileCodec::default(), TileCodec::Png, TileCodec::WebP] {
+            assert_eq!(codec.to_string().parse::<TileCodec>()?, codec);
+        }
+        let json = serde_json::to_string(&TileCodec::Jpeg { quality: 80 })?;
+        assert_eq!(json, "\"jpeg:80\"");
+        Ok(())
+    }
+
+    #[test]
+    fn lossless_codecs() -> Result<()> {
+        let mut image = RgbImage::from_pixel(8, 8, Rgb([120, 121, 122]));
+        image.put_pixel(3, 4, Rgb([1, 200, 3]));
+        for codec in [TileCodec::Png, TileCodec::WebP] {
+            let data = codec.encode(&image)?;
+            assert_eq!(image::guess_format(&data)?, codec.format());
+            assert_eq!(codec.decode(&data)?, image);
+        }
+        let data = TileCodec::Jpeg { quality: 50 }.encode(&image)?;
+        assert_eq!(image::guess_format(&data)?, image::ImageFormat::Jpeg);
+        Ok(())
+    }
+}